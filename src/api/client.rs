@@ -8,7 +8,6 @@ use anyhow::{Context, Error};
 use graphql_client::Response;
 use once_cell::sync::OnceCell;
 use reqwest_tracing::OtelName;
-use serde_json;
 #[cfg(test)]
 use std::convert::identity;
 use tracing::{instrument, warn};
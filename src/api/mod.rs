@@ -7,30 +7,36 @@ mod operations;
 pub use client::Client;
 pub use operations::ReleaseState;
 
-use self::operations::{GetSite, GetSiteResponse};
+use self::operations::{GetSite, GetSiteResponse, UpdateRelease};
+use crate::pages::DeploymentResult;
 
 #[instrument]
 pub async fn update_release(client: &Client, state: operations::ReleaseState) {
+    send_update_release(client, UpdateRelease::new(state)).await;
+}
+
+/// Mark the release as done, with the deployment serving it when the target creates one
+#[instrument]
+pub async fn release_done(client: &Client, deployment: Option<&DeploymentResult>) {
+    let op = UpdateRelease::new(ReleaseState::Done);
+    let op = match deployment {
+        Some(deployment) => op.with_deployment(deployment),
+        None => op,
+    };
+    send_update_release(client, op).await;
+}
+
+async fn send_update_release(client: &Client, op: UpdateRelease) {
     let release_id = &client.meta.release_id;
     if release_id.is_empty() {
         return;
     }
 
-    if let Err(err) = update_release_inner(client, state).await {
+    if let Err(err) = client.send(op).await {
         sentry_anyhow::capture_anyhow(&err);
     }
 }
 
-#[instrument]
-async fn update_release_inner(
-    client: &Client,
-    state: operations::ReleaseState,
-) -> anyhow::Result<()> {
-    let op = operations::UpdateRelease::new(state);
-    client.send(op).await?;
-    Ok(())
-}
-
 #[instrument]
 pub async fn get_site(client: &Client) -> anyhow::Result<Option<GetSiteResponse>> {
     client.send(GetSite::new()).await
@@ -5,6 +5,6 @@ mod update_release;
 #[cfg(test)]
 mod test_helper;
 
-pub use get_site::{GetSite, GetSiteResponse, GetSiteResponseInner};
+pub use get_site::{GetSite, GetSiteResponse};
 pub use types::*;
 pub use update_release::UpdateRelease;
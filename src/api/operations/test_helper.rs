@@ -1,13 +1,15 @@
 #![cfg(test)]
 use crate::{
     api::{client::Client, operation::Operation},
+    environment::EnvironmentRegistry,
     types::DeployMeta,
 };
 use std::env;
 
-pub async fn assert_operation<'a>(op: impl Operation) {
+pub async fn assert_operation(op: impl Operation) {
     let (client_id, release_id, token) = init_env();
     let meta = DeployMeta {
+        environment: EnvironmentRegistry::default().resolve(&client_id).cloned(),
         client_id,
         release_id,
         token: Some(token),
@@ -1,6 +1,7 @@
 use super::types::ReleaseState;
 use crate::{
     api::operation::{Operation, ToResponse},
+    pages::DeploymentResult,
     types::DeployMeta,
 };
 use graphql_client::QueryBody;
@@ -9,11 +10,24 @@ use serde_derive::{Deserialize, Serialize};
 #[derive(Debug)]
 pub struct UpdateRelease {
     state: ReleaseState,
+    deployment_id: Option<String>,
+    deployment_url: Option<String>,
 }
 
 impl UpdateRelease {
     pub fn new(state: ReleaseState) -> Self {
-        Self { state }
+        Self {
+            state,
+            deployment_id: None,
+            deployment_url: None,
+        }
+    }
+
+    /// Record the deployment which serves the release
+    pub fn with_deployment(mut self, deployment: &DeploymentResult) -> Self {
+        self.deployment_id.clone_from(&deployment.deployment_id);
+        self.deployment_url.clone_from(&deployment.url);
+        self
     }
 }
 
@@ -30,6 +44,8 @@ impl Operation for UpdateRelease {
             input: UpdateReleaseInput {
                 id: &meta.release_id,
                 state: self.state,
+                deployment_id: self.deployment_id.as_deref(),
+                deployment_url: self.deployment_url.as_deref(),
             },
         })
     }
@@ -40,9 +56,14 @@ impl ToResponse for UpdateRelease {
 }
 
 #[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct UpdateReleaseInput<'a> {
     pub id: &'a str,
     pub state: ReleaseState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deployment_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deployment_url: Option<&'a str>,
 }
 
 #[derive(Serialize, Debug)]
@@ -65,7 +86,7 @@ pub struct UpdateReleaseResponse {
 }
 
 #[inline]
-fn build_query<'a>(variables: UpdateReleaseVariables<'_>) -> QueryBody<UpdateReleaseVariables<'_>> {
+fn build_query(variables: UpdateReleaseVariables<'_>) -> QueryBody<UpdateReleaseVariables<'_>> {
     QueryBody {
         variables,
         query: include_str!("./update_release.gql"),
@@ -83,4 +104,34 @@ mod tests {
     async fn test_operation_work() {
         assert_operation(UpdateRelease::new(ReleaseState::Done)).await;
     }
+
+    #[test]
+    fn test_deployment_input() {
+        let meta = DeployMeta {
+            release_id: "R1".to_owned(),
+            ..Default::default()
+        };
+        let input = |op: &UpdateRelease| serde_json::to_value(&op.request(&meta).variables.input);
+
+        let op = UpdateRelease::new(ReleaseState::Done);
+        assert_eq!(
+            input(&op).unwrap(),
+            serde_json::json!({ "id": "R1", "state": "done" })
+        );
+
+        let op = op.with_deployment(&DeploymentResult {
+            deployment_id: Some("D1".to_owned()),
+            url: Some("https://d1.p1.pages.dev".to_owned()),
+            ..Default::default()
+        });
+        assert_eq!(
+            input(&op).unwrap(),
+            serde_json::json!({
+                "id": "R1",
+                "state": "done",
+                "deploymentId": "D1",
+                "deploymentUrl": "https://d1.p1.pages.dev",
+            })
+        );
+    }
 }
@@ -0,0 +1,342 @@
+use aws_sdk_s3::{
+    error::SdkError,
+    types::{Delete, ObjectIdentifier},
+    Client,
+};
+use aws_smithy_types::{byte_stream::ByteStream, DateTime};
+use serde_derive::{Deserialize, Serialize};
+use std::{cmp::Reverse, collections::HashSet, time::SystemTime};
+use tracing::{debug, info, instrument, warn};
+
+use crate::{
+    claim::{self, ClaimState},
+    config::Config,
+};
+
+/// Most keys accepted by a single `delete_objects` request
+const DELETE_BATCH_SIZE: usize = 1000;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Aws(#[from] Box<dyn std::error::Error + Send + Sync>),
+    #[error("invalid manifest {key}")]
+    Manifest {
+        key: String,
+        #[source]
+        source: serde_json::Error,
+    },
+}
+
+/// Keys of the `_nuxt` assets uploaded by a release
+///
+/// Stored as `{client_id}/_nuxt-manifests/{release_id}.json`, the last modified time of the object
+/// orders the releases.
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    release_id: String,
+    keys: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GcSummary {
+    /// Releases with a manifest
+    pub releases: usize,
+    /// Assets referenced by the kept releases
+    pub live: usize,
+    /// Stale assets and manifests found, deleted unless it is a dry run
+    pub stale: usize,
+    pub deleted: usize,
+    pub failed: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Object {
+    key: String,
+    last_modified: DateTime,
+}
+
+#[inline]
+fn manifest_prefix(client_id: &str) -> String {
+    format!("{client_id}/_nuxt-manifests/")
+}
+
+#[inline]
+fn asset_prefix(client_id: &str) -> String {
+    format!("{client_id}/_nuxt/")
+}
+
+/// Record the assets of a release, so they are kept while the release is one of the latest
+#[instrument(err, skip(client, config, keys), fields(keys = keys.len()))]
+pub async fn write_manifest(
+    client: &Client,
+    config: &Config,
+    client_id: &str,
+    release_id: &str,
+    keys: Vec<String>,
+) -> Result<(), Error> {
+    let manifest = Manifest {
+        release_id: release_id.to_owned(),
+        keys,
+    };
+    let body = serde_json::to_vec(&manifest).expect("manifest is always serializable");
+
+    client
+        .put_object()
+        .bucket(&config.r2.bucket)
+        .key(format!("{}{release_id}.json", manifest_prefix(client_id)))
+        .content_type("application/json")
+        .body(ByteStream::from(body))
+        .send()
+        .await
+        .map_err(aws_error)?;
+    Ok(())
+}
+
+/// Whether the release already has a manifest
+#[instrument(err, skip(client, config))]
+pub async fn manifest_exists(
+    client: &Client,
+    config: &Config,
+    client_id: &str,
+    release_id: &str,
+) -> Result<bool, Error> {
+    let res = client
+        .head_object()
+        .bucket(&config.r2.bucket)
+        .key(format!("{}{release_id}.json", manifest_prefix(client_id)))
+        .send()
+        .await;
+    match res {
+        Ok(_) => Ok(true),
+        Err(SdkError::ServiceError(err)) if err.err().is_not_found() => Ok(false),
+        Err(err) => Err(aws_error(err)),
+    }
+}
+
+/// Forget a release which was never published, its assets are collected unless another release
+/// uses them
+#[instrument(err, skip(client, config))]
+pub async fn delete_manifest(
+    client: &Client,
+    config: &Config,
+    client_id: &str,
+    release_id: &str,
+) -> Result<(), Error> {
+    client
+        .delete_object()
+        .bucket(&config.r2.bucket)
+        .key(format!("{}{release_id}.json", manifest_prefix(client_id)))
+        .send()
+        .await
+        .map_err(aws_error)?;
+    Ok(())
+}
+
+/// Delete the assets which are not referenced by the last `gc.keep_releases` releases
+///
+/// Nothing is deleted until enough releases have a manifest, as the assets of older releases are
+/// unknown, and recent assets are always kept for HTML pages still cached at the edge. Releases
+/// of a client finishing together are collected once, under a claim in the R2 bucket.
+#[instrument(err, skip(client, config))]
+pub async fn collect(
+    client: &Client,
+    config: &Config,
+    client_id: &str,
+) -> Result<GcSummary, Error> {
+    let claim_id = format!("gc/{client_id}");
+    let ClaimState::Claimed(claim) =
+        claim::claim(client, &config.r2.bucket, &claim_id, config.claim_ttl())
+            .await
+            .map_err(aws_error)?
+    else {
+        info!("collected by another worker, skip");
+        return Ok(GcSummary::default());
+    };
+
+    let res = collect_claimed(client, config, client_id).await;
+    claim.release().await;
+    res
+}
+
+async fn collect_claimed(
+    client: &Client,
+    config: &Config,
+    client_id: &str,
+) -> Result<GcSummary, Error> {
+    let gc = &config.gc;
+    let bucket = &config.r2.bucket;
+    let mut summary = GcSummary::default();
+
+    let manifests = list_objects(client, bucket, &manifest_prefix(client_id)).await?;
+    summary.releases = manifests.len();
+    let Some((kept, stale_manifests)) = split_releases(manifests, gc.keep_releases) else {
+        info!(
+            releases = summary.releases,
+            "not enough releases tracked, skip"
+        );
+        return Ok(summary);
+    };
+
+    let mut live = HashSet::new();
+    for manifest in &kept {
+        live.extend(get_manifest(client, bucket, &manifest.key).await?.keys);
+    }
+    summary.live = live.len();
+
+    let cutoff = DateTime::from(SystemTime::now()).secs() - gc.min_age_secs as i64;
+    let assets = list_objects(client, bucket, &asset_prefix(client_id)).await?;
+    let mut stale = stale_assets(assets, &live, cutoff);
+    // manifests go last, so an interrupted run still knows the releases next time
+    stale.extend(stale_manifests.into_iter().map(|manifest| manifest.key));
+    summary.stale = stale.len();
+
+    if gc.dry_run {
+        info!(?stale, "dry run, skip delete");
+        return Ok(summary);
+    }
+
+    for batch in stale.chunks(DELETE_BATCH_SIZE) {
+        let failed = delete_objects(client, bucket, batch).await?;
+        summary.failed += failed;
+        summary.deleted += batch.len() - failed;
+    }
+
+    info!(?summary, "collect stale assets");
+    Ok(summary)
+}
+
+/// Split manifests into the kept releases and the stale ones, `None` if there are not enough
+fn split_releases(mut manifests: Vec<Object>, keep: usize) -> Option<(Vec<Object>, Vec<Object>)> {
+    if manifests.len() < keep {
+        return None;
+    }
+    manifests.sort_by_key(|manifest| {
+        Reverse((
+            manifest.last_modified.secs(),
+            manifest.last_modified.subsec_nanos(),
+        ))
+    });
+    let stale = manifests.split_off(keep);
+    Some((manifests, stale))
+}
+
+fn stale_assets(assets: Vec<Object>, live: &HashSet<String>, cutoff: i64) -> Vec<String> {
+    assets
+        .into_iter()
+        .filter(|asset| !live.contains(&asset.key) && asset.last_modified.secs() < cutoff)
+        .map(|asset| asset.key)
+        .collect()
+}
+
+async fn get_manifest(client: &Client, bucket: &str, key: &str) -> Result<Manifest, Error> {
+    let object = client
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .send()
+        .await
+        .map_err(aws_error)?;
+    let body = object.body.collect().await.map_err(aws_error)?.into_bytes();
+    serde_json::from_slice(&body).map_err(|source| Error::Manifest {
+        key: key.to_owned(),
+        source,
+    })
+}
+
+async fn list_objects(client: &Client, bucket: &str, prefix: &str) -> Result<Vec<Object>, Error> {
+    let mut objects = Vec::new();
+    let mut pages = client
+        .list_objects_v2()
+        .bucket(bucket)
+        .prefix(prefix)
+        .into_paginator()
+        .send();
+
+    while let Some(page) = pages.next().await {
+        let page = page.map_err(aws_error)?;
+        objects.extend(page.contents().iter().filter_map(|object| {
+            Some(Object {
+                key: object.key()?.to_owned(),
+                last_modified: *object.last_modified()?,
+            })
+        }));
+    }
+
+    debug!(prefix, objects = objects.len(), "list objects");
+    Ok(objects)
+}
+
+/// Returns the number of keys which fail to be deleted
+async fn delete_objects(client: &Client, bucket: &str, keys: &[String]) -> Result<usize, Error> {
+    let objects = keys
+        .iter()
+        .map(|key| ObjectIdentifier::builder().key(key).build())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(aws_error)?;
+    let delete = Delete::builder()
+        .set_objects(Some(objects))
+        .quiet(true)
+        .build()
+        .map_err(aws_error)?;
+
+    let output = client
+        .delete_objects()
+        .bucket(bucket)
+        .delete(delete)
+        .send()
+        .await
+        .map_err(aws_error)?;
+
+    for err in output.errors() {
+        warn!(
+            key = err.key(),
+            code = err.code(),
+            message = err.message(),
+            "fail to delete object"
+        );
+    }
+    Ok(output.errors().len())
+}
+
+fn aws_error(err: impl std::error::Error + Send + Sync + 'static) -> Error {
+    Error::from(Box::new(err) as Box<dyn std::error::Error + Send + Sync>)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(key: &str, secs: i64) -> Object {
+        Object {
+            key: key.to_owned(),
+            last_modified: DateTime::from_secs(secs),
+        }
+    }
+
+    #[test]
+    fn test_select_stale() {
+        let manifests = vec![
+            object("P1/_nuxt-manifests/a.json", 100),
+            object("P1/_nuxt-manifests/c.json", 300),
+            object("P1/_nuxt-manifests/b.json", 200),
+        ];
+        assert!(split_releases(manifests.clone(), 4).is_none());
+
+        let (kept, stale) = split_releases(manifests, 2).unwrap();
+        let keys = |objects: &[Object]| objects.iter().map(|o| o.key.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            keys(&kept),
+            ["P1/_nuxt-manifests/c.json", "P1/_nuxt-manifests/b.json"]
+        );
+        assert_eq!(keys(&stale), ["P1/_nuxt-manifests/a.json"]);
+
+        let live = HashSet::from(["P1/_nuxt/live.js".to_owned()]);
+        let assets = vec![
+            object("P1/_nuxt/live.js", 100),
+            object("P1/_nuxt/old.js", 100),
+            object("P1/_nuxt/recent.js", 500),
+        ];
+        assert_eq!(stale_assets(assets, &live, 400), ["P1/_nuxt/old.js"]);
+    }
+}
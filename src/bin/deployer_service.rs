@@ -1,23 +1,65 @@
 use aws_config::BehaviorVersion;
 use aws_lambda_events::s3::{S3Event, S3EventRecord};
-use aws_sdk_sqs::{types::Message, Client, Error};
+use aws_sdk_sqs::{
+    types::{Message, MessageSystemAttributeName},
+    Client, Error,
+};
 use deployer::{
-    bootstrap, health_check::HealthCheck, heartbeat::HeartBeat, s3_handler, test_event::TestEvent,
+    bootstrap,
+    circuit_breaker::CircuitBreaker,
+    config::{Config, QueueConfig},
+    health_check::HealthCheck,
+    heartbeat::HeartBeat,
+    metric,
+    notification::Notification,
+    s3_handler,
+    sqs_error::{self, SqsErrorKind},
+};
+use futures::FutureExt;
+use std::{
+    collections::HashSet,
+    fmt,
+    future::Future,
+    panic::AssertUnwindSafe,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
-use std::{env, fmt, time::Duration};
 use tokio::{
     select,
     signal::unix::{signal, SignalKind},
-    sync::oneshot,
-    task,
-    time::{interval, MissedTickBehavior},
+    sync::{oneshot, Semaphore},
+    task::{self, JoinError, JoinSet},
+    time::{sleep, timeout},
 };
-use tracing::{error, info, instrument};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, instrument, warn};
+
+/// Time for cancelled deployments to report their release state before giving up on them
+const CANCEL_GRACE: Duration = Duration::from_secs(5);
+/// Upper bound of `max_number_of_messages` accepted by SQS
+const MAX_NUMBER_OF_MESSAGES: usize = 10;
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     info!("starting standalone deployer service");
-    let _guard = bootstrap::init();
+    let config = Arc::new(Config::load()?);
+    anyhow::ensure!(
+        !config.queue.url.is_empty(),
+        "queue.url is required, set AWS_QUEUE_URL"
+    );
+    // every message would be rejected, then dead-lettered
+    anyhow::ensure!(
+        !config.deploy_meta_keys.is_empty() || config.allow_unsigned_meta,
+        "deploy_meta_keys is required, set DEPLOY_META_KEYS or ALLOW_UNSIGNED_META"
+    );
+    let _guard = bootstrap::init(&config);
+    if config.deploy_meta_keys.is_empty() {
+        warn!("DEPLOY_META_KEYS is not set, deploy meta signature is not verified");
+    }
     let (request_stop, mut stop_receiver) = oneshot::channel();
     task::spawn(async {
         let mut interrupt = signal(SignalKind::interrupt()).expect("Fail to listen ctrl+c");
@@ -33,26 +75,182 @@ async fn main() -> Result<(), anyhow::Error> {
         request_stop.send(()).expect("Fail to send request stop");
     });
 
-    let mut timer = interval(Duration::from_secs(30));
     let shared_config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let sqs_client = sqs_client(&shared_config);
-
-    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let sqs_client = sqs_client(&shared_config, config.queue.localstack);
+    let cw_client = aws_sdk_cloudwatch::Client::new(&shared_config);
 
-    let queue_url = env::var("AWS_QUEUE_URL").expect("No AWS_QUEUE_URL");
+    let workers = Arc::new(Semaphore::new(config.queue.workers));
+    let shutdown = CancellationToken::new();
+    let mut pool = WorkerPool::default();
+    let mut breaker = CircuitBreaker::new("sqs", &config.queue.breaker);
+    let mut fatal_error = None;
     loop {
-        select! {
-            _ = timer.tick() => (),
+        // wait until at least one worker is free
+        let permit = select! {
+            permit = workers.clone().acquire_owned() => permit.expect("worker pool closed"),
+            _ = &mut stop_receiver => {
+                break;
+            }
+        };
+        let capacity = (workers.available_permits() + 1).min(MAX_NUMBER_OF_MESSAGES);
+
+        let res = select! {
+            res = receive(&sqs_client, &config, capacity) => res,
             _ = &mut stop_receiver => {
                 break;
             }
+        };
+
+        let messages = match res {
+            Ok(messages) => {
+                breaker.success();
+                messages
+            }
+            Err(err) => {
+                let kind = sqs_error::classify(&err);
+                sentry::capture_error(&err);
+                metric::sqs_error(&cw_client, kind).await;
+
+                if kind == SqsErrorKind::Fatal {
+                    error!(?err, "unrecoverable SQS error, stop receiving messages");
+                    fatal_error = Some(err);
+                    break;
+                }
+
+                let delay = breaker.failure();
+                warn!(?err, ?delay, "transient SQS error, retry later");
+                select! {
+                    _ = sleep(delay) => continue,
+                    _ = &mut stop_receiver => break,
+                }
+            }
+        };
+
+        let mut permit = Some(permit);
+        for message in messages {
+            let permit = match permit.take() {
+                Some(permit) => permit,
+                None => workers
+                    .clone()
+                    .try_acquire_owned()
+                    .expect("received more messages than free workers"),
+            };
+            let client = sqs_client.clone();
+            let config = config.clone();
+            let shutdown = shutdown.clone();
+            pool.spawn(message, |message| async move {
+                let settled = handle_message(&client, config, &message, shutdown).await;
+                drop(permit);
+                settled
+            });
+        }
+
+        pool.reap();
+    }
+
+    pool.drain(&sqs_client, &config.queue, &shutdown).await;
+
+    match fatal_error {
+        Some(err) => Err(anyhow::Error::new(err).context("Unrecoverable SQS error")),
+        None => Ok(()),
+    }
+}
+
+/// In-flight deployments, each task yields the receipt handle of its message and whether the
+/// message is settled
+#[derive(Default)]
+struct WorkerPool {
+    tasks: JoinSet<(Option<String>, bool)>,
+    receipt_handles: HashSet<String>,
+}
+
+impl WorkerPool {
+    fn spawn<F, Fut>(&mut self, message: Message, f: F)
+    where
+        F: FnOnce(Message) -> Fut,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        let receipt_handle = message.receipt_handle().map(ToOwned::to_owned);
+        self.receipt_handles.extend(receipt_handle.clone());
+        let worker = f(message);
+        self.tasks.spawn(async move {
+            // catch the panic here so the receipt handle is not lost with the task
+            let settled = AssertUnwindSafe(worker)
+                .catch_unwind()
+                .await
+                .unwrap_or_else(|_| {
+                    error!("worker crashed");
+                    false
+                });
+            (receipt_handle, settled)
+        });
+    }
+
+    /// Forget finished deployments
+    fn reap(&mut self) {
+        while let Some(res) = self.tasks.try_join_next() {
+            self.finish(res);
+        }
+    }
+
+    /// Returns the receipt handle of the finished message if it is not settled
+    fn finish(&mut self, res: Result<(Option<String>, bool), JoinError>) -> Option<String> {
+        match res {
+            Ok((receipt_handle, settled)) => {
+                let receipt_handle = receipt_handle?;
+                self.receipt_handles.remove(&receipt_handle);
+                (!settled).then_some(receipt_handle)
+            }
+            Err(err) => {
+                error!(?err, "worker crashed");
+                sentry::capture_error(&err);
+                None
+            }
         }
-        receive(&sqs_client, &queue_url)
-            .await
-            .expect("receive error");
     }
 
-    Ok(())
+    /// Wait for in-flight deployments until the shutdown deadline, then cancel the remaining
+    /// ones and make their messages visible again so other workers can pick them up
+    #[instrument(skip_all, fields(in_flight = self.tasks.len()))]
+    async fn drain(mut self, client: &Client, queue: &QueueConfig, shutdown: &CancellationToken) {
+        info!("wait for in-flight deployments");
+        let deadline = queue.shutdown_timeout();
+        let res = timeout(deadline, async {
+            while let Some(res) = self.tasks.join_next().await {
+                self.finish(res);
+            }
+        })
+        .await;
+
+        if res.is_ok() {
+            info!("all in-flight deployments finished");
+            return;
+        }
+
+        warn!(
+            remaining = self.tasks.len(),
+            ?deadline,
+            "shutdown deadline reached, cancel in-flight deployments"
+        );
+        shutdown.cancel();
+
+        let mut unsettled = Vec::new();
+        let _ = timeout(CANCEL_GRACE, async {
+            while let Some(res) = self.tasks.join_next().await {
+                unsettled.extend(self.finish(res));
+            }
+        })
+        .await;
+
+        // deployments which are still not stopped after the grace period are aborted, and waited
+        // for so none of them still runs once their messages are visible again
+        self.tasks.shutdown().await;
+        unsettled.extend(self.receipt_handles);
+
+        for receipt_handle in unsettled {
+            release_message(client, &queue.url, &receipt_handle).await;
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -85,81 +283,218 @@ impl<'a> fmt::Debug for S3EventFiles<'a> {
     }
 }
 
-#[instrument(skip(client))]
-async fn receive(client: &Client, queue_url: &str) -> Result<(), Error> {
-    let guard = HealthCheck::start().await;
-    let rcv_message_output = client.receive_message().queue_url(queue_url).send().await?;
+#[instrument(skip(client, config))]
+async fn receive(
+    client: &Client,
+    config: &Config,
+    max_messages: usize,
+) -> Result<Vec<Message>, Error> {
+    let guard = HealthCheck::start(&config.sentry_cron_check_url).await;
+    let rcv_message_output = client
+        .receive_message()
+        .queue_url(&config.queue.url)
+        .max_number_of_messages(max_messages as i32)
+        .wait_time_seconds(config.queue.wait_time_seconds)
+        .message_system_attribute_names(MessageSystemAttributeName::ApproximateReceiveCount)
+        .send()
+        .await?;
 
-    let messages = rcv_message_output.messages();
+    let messages = rcv_message_output.messages.unwrap_or_default();
 
     if messages.is_empty() {
         info!("no message");
     }
 
-    for message in messages {
-        if let Some(body) = message.body() {
-            let heartbeat = HeartBeat::new(client, queue_url, message.receipt_handle().unwrap());
-            heartbeat
-                .run(|| async {
-                    match serde_json::from_str::<S3Event>(body) {
-                        Ok(event) => {
-                            let event_files = S3EventFiles(&event);
-                            let handle = message.receipt_handle();
-                            info!(?event_files, ?handle, "receive s3 event");
-
-                            let res = s3_handler::handle_s3_event(event).await;
-
-                            // only clean the message when success
-                            if res.is_ok() {
-                                // TODO: consider batch clean up messages
-                                info!(?handle, "delete message");
-                                delete_message(client, queue_url, message).await;
-                            }
-                        }
+    guard.finish().await;
+
+    Ok(messages)
+}
+
+/// Returns whether the message is settled (deleted) after handling
+#[instrument(skip(client, config, message, shutdown), fields(message_id = message.message_id()))]
+async fn handle_message(
+    client: &Client,
+    config: Arc<Config>,
+    message: &Message,
+    shutdown: CancellationToken,
+) -> bool {
+    let (Some(body), Some(receipt_handle)) = (message.body(), message.receipt_handle()) else {
+        warn!("message without body or receipt handle");
+        return false;
+    };
+
+    let receive_count = receive_count(message);
+    let queue = &config.queue;
+    let queue_url = queue.url.as_str();
+    let max_receive_count = queue.max_receive_count;
 
-                        Err(err) => match TestEvent::from_str(body) {
-                            Ok(event) if event.is_storipress_bucket() => {
-                                info!("receive test event");
-                                delete_message(client, queue_url, message).await;
+    let settled = AtomicBool::new(false);
+    let heartbeat = HeartBeat::new(
+        client,
+        queue_url,
+        receipt_handle,
+        queue.heartbeat_timeout_secs,
+    );
+    heartbeat
+        .run(|| async {
+            let res = match Notification::from_str(body) {
+                Ok(Notification::S3(event)) if receive_count > max_receive_count => {
+                    warn!(receive_count, "give up message after too many attempts");
+                    s3_handler::abandon_s3_event(event, &config, "too many attempts").await;
+                    dead_letter(client, queue, message, "too many attempts").await
+                }
+                Ok(Notification::S3(event)) => {
+                    let event_files = S3EventFiles(&event);
+                    let handle = message.receipt_handle();
+                    info!(?event_files, ?handle, receive_count, "receive s3 event");
+
+                    let res = s3_handler::handle_s3_event(
+                        event.clone(),
+                        config.clone(),
+                        shutdown.clone(),
+                    )
+                    .await;
+
+                    match res {
+                        // only clean the message when success
+                        Ok(_) => {
+                            // TODO: consider batch clean up messages
+                            info!(?handle, "delete message");
+                            delete_message(client, queue_url, message).await
+                        }
+                        // another worker is deploying, check again after it should be done
+                        Err(res) if res.failed.is_empty() => {
+                            info!(deferred = ?res.deferred, "defer message");
+                            if let Some(handle) = handle {
+                                change_visibility(client, queue_url, handle, queue.defer_secs)
+                                    .await;
                             }
-                            Ok(event) => error!(?event, body, "Unknown event"),
-                            Err(_) => error!(?err, body, "Fail to parse message"),
-                        },
+                            false
+                        }
+                        Err(_)
+                            if receive_count >= max_receive_count && !shutdown.is_cancelled() =>
+                        {
+                            warn!(receive_count, "give up message after too many attempts");
+                            s3_handler::abandon_s3_event(event, &config, "too many attempts").await;
+                            dead_letter(client, queue, message, "too many attempts").await
+                        }
+                        Err(_) => false,
                     }
-                })
-                .await;
-        }
-    }
+                }
 
-    guard.finish().await;
+                Ok(Notification::Test(event)) if event.is_storipress_bucket() => {
+                    info!("receive test event");
+                    delete_message(client, queue_url, message).await
+                }
+                Ok(Notification::Test(event)) => {
+                    error!(?event, body, "Unknown event");
+                    dead_letter(client, queue, message, "unknown event").await
+                }
+                Ok(Notification::Ignored(reason)) => {
+                    info!(reason, "ignore notification");
+                    delete_message(client, queue_url, message).await
+                }
+                Err(err) => {
+                    error!(?err, body, "Fail to parse message");
+                    dead_letter(client, queue, message, "unparsable message").await
+                }
+            };
+            settled.store(res, Ordering::Relaxed);
+        })
+        .await;
 
-    Ok(())
+    settled.into_inner()
 }
 
+/// Returns whether the message is deleted
 #[instrument(skip(message))]
-async fn delete_message(client: &Client, queue_url: &str, message: &Message) {
-    if let Some(handle) = message.receipt_handle() {
-        if let Err(err) = client
-            .delete_message()
-            .queue_url(queue_url)
-            .receipt_handle(handle)
-            .send()
-            .await
-        {
-            sentry::capture_error(&err);
+async fn delete_message(client: &Client, queue_url: &str, message: &Message) -> bool {
+    let Some(handle) = message.receipt_handle() else {
+        return false;
+    };
+
+    if let Err(err) = client
+        .delete_message()
+        .queue_url(queue_url)
+        .receipt_handle(handle)
+        .send()
+        .await
+    {
+        sentry::capture_error(&err);
+        return false;
+    }
+    true
+}
+
+/// Move the message to the dead-letter queue, or only delete it when no dead-letter queue is
+/// configured. Returns whether the message is removed from the queue.
+#[instrument(skip(client, message), fields(message_id = message.message_id()))]
+async fn dead_letter(
+    client: &Client,
+    queue: &QueueConfig,
+    message: &Message,
+    reason: &str,
+) -> bool {
+    sentry::capture_message(
+        &format!("Dead letter message: {reason}"),
+        sentry::Level::Warning,
+    );
+
+    match &queue.dead_letter_queue_url {
+        Some(dead_letter_queue_url) => {
+            info!(dead_letter_queue_url, "move message to dead-letter queue");
+            if let Err(err) = client
+                .send_message()
+                .queue_url(dead_letter_queue_url)
+                .set_message_body(message.body().map(ToOwned::to_owned))
+                .send()
+                .await
+            {
+                error!(?err, "Fail to move message to dead-letter queue");
+                sentry::capture_error(&err);
+                return false;
+            }
         }
+        None => warn!("no dead-letter queue, drop message"),
     }
+
+    delete_message(client, &queue.url, message).await
 }
 
-#[instrument]
-fn use_localstack() -> bool {
-    std::env::var("LOCALSTACK").unwrap_or_default() == "true"
+/// How many times the message is received, including the current one
+fn receive_count(message: &Message) -> u32 {
+    message
+        .attributes()
+        .and_then(|attributes| attributes.get(&MessageSystemAttributeName::ApproximateReceiveCount))
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(1)
+}
+
+/// Reset the visibility timeout so the message is redelivered immediately
+async fn release_message(client: &Client, queue_url: &str, receipt_handle: &str) {
+    change_visibility(client, queue_url, receipt_handle, 0).await
+}
+
+#[instrument(skip(client))]
+async fn change_visibility(client: &Client, queue_url: &str, receipt_handle: &str, secs: i32) {
+    info!("change message visibility");
+    if let Err(err) = client
+        .change_message_visibility()
+        .queue_url(queue_url)
+        .receipt_handle(receipt_handle)
+        .visibility_timeout(secs)
+        .send()
+        .await
+    {
+        error!(?err, "Fail to change message visibility");
+        sentry::capture_error(&err);
+    }
 }
 
 #[instrument]
-fn sqs_client(conf: &aws_types::SdkConfig) -> aws_sdk_sqs::Client {
+fn sqs_client(conf: &aws_types::SdkConfig, localstack: bool) -> aws_sdk_sqs::Client {
     let mut sqs_config_builder = aws_sdk_sqs::config::Builder::from(conf);
-    if use_localstack() {
+    if localstack {
         sqs_config_builder = sqs_config_builder.endpoint_url("http://localhost:4566/")
     }
     aws_sdk_sqs::Client::from_conf(sqs_config_builder.build())
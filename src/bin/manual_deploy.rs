@@ -0,0 +1,120 @@
+// the deploy pipeline future is too deep for the default limit
+#![recursion_limit = "256"]
+
+use anyhow::Context;
+use clap::Parser;
+use deployer::{
+    bootstrap, config::Config, environment::EnvironmentRegistry, s3_handler, types::DeployMeta,
+};
+use serde_json::{Map, Value};
+use std::{fs, path::PathBuf};
+use tracing::info;
+
+/// Deploy a local archive with the same pipeline as the deployer service
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Path to the archive, `.tar.br`, `.tar.gz`, `.tar.zst`, `.tar` or `.zip`
+    archive: PathBuf,
+
+    /// Deploy meta as JSON, same format as the `sp-deploy` S3 metadata
+    #[arg(long, conflicts_with = "meta_file")]
+    meta: Option<String>,
+
+    /// Path to a JSON file containing the deploy meta
+    #[arg(long)]
+    meta_file: Option<PathBuf>,
+
+    #[arg(long)]
+    page_id: Option<String>,
+
+    #[arg(long)]
+    client_id: Option<String>,
+
+    #[arg(long)]
+    release_id: Option<String>,
+
+    #[arg(long)]
+    source: Option<String>,
+
+    #[arg(long)]
+    output_path: Option<String>,
+
+    /// Storipress API token used to update the release state
+    #[arg(long, env = "DEPLOY_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// `static` or `cloudflare_function`
+    #[arg(long)]
+    deploy_type: Option<String>,
+
+    /// `cloudflare`, `s3_static` or `local_dir`
+    #[arg(long)]
+    target: Option<String>,
+}
+
+impl Args {
+    /// Load meta from `--meta` or `--meta-file`, then apply the flag overrides
+    fn into_meta(
+        self,
+        environments: &EnvironmentRegistry,
+    ) -> anyhow::Result<(DeployMeta, PathBuf)> {
+        let json = match (self.meta, self.meta_file) {
+            (Some(json), _) => Some(json),
+            (None, Some(path)) => Some(
+                fs::read_to_string(&path)
+                    .with_context(|| format!("Fail to read meta file {}", path.display()))?,
+            ),
+            (None, None) => None,
+        };
+
+        let mut meta = match json {
+            Some(json) => {
+                serde_json::from_str::<Map<String, Value>>(&json).context("Invalid meta")?
+            }
+            None => Map::new(),
+        };
+
+        let overrides = [
+            ("page_id", self.page_id),
+            ("client_id", self.client_id),
+            ("release_id", self.release_id),
+            ("source", self.source),
+            ("output_path", self.output_path),
+            ("token", self.token),
+            ("deploy_type", self.deploy_type),
+            ("target", self.target),
+        ];
+        for (field, value) in overrides {
+            if let Some(value) = value {
+                meta.insert(field.to_owned(), Value::String(value));
+            }
+        }
+
+        // validate after the overrides, so flags can fill fields missing in the meta
+        let meta = DeployMeta::parse(&Value::Object(meta).to_string(), environments).map_err(
+            |errors| {
+                let errors = errors.iter().map(ToString::to_string).collect::<Vec<_>>();
+                anyhow::anyhow!("Invalid meta: {}", errors.join(", "))
+            },
+        )?;
+
+        Ok((meta, self.archive))
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    dotenvy::dotenv().ok();
+    let args = Args::parse();
+    let config = Config::load()?;
+    let _guard = bootstrap::init(&config);
+
+    let (meta, archive) = args.into_meta(&config.environments)?;
+    info!(?meta, archive = %archive.display(), "start manual deploy");
+
+    let summary = s3_handler::process_local_file(&config, meta, &archive).await?;
+    info!(?summary, "manual deploy success");
+
+    Ok(())
+}
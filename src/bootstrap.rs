@@ -1,19 +1,20 @@
-use crate::constants::SENTRY_DSN;
+use crate::config::Config;
 use sentry::ClientInitGuard;
 use std::env;
 use tracing::Level;
 use tracing_subscriber::{filter::filter_fn, prelude::*, EnvFilter};
 
+#[allow(dead_code)] // only held to flush on drop
 pub struct BootstrapGuard(ClientInitGuard, tracing_axiom::Guard);
 
-pub fn init() -> BootstrapGuard {
+pub fn init(config: &Config) -> BootstrapGuard {
     let (axiom_layer, axiom_guard) = tracing_axiom::builder()
         .with_service_name("deployer")
         .layer()
         .expect("Fail to init axiom layer");
 
     let guard = sentry::init((
-        SENTRY_DSN,
+        config.sentry_dsn.as_str(),
         sentry::ClientOptions {
             release: sentry::release_name!(),
             traces_sample_rate: 0.0,
@@ -50,6 +51,7 @@ mod tests {
     use tracing::Level;
 
     #[test]
+    #[allow(clippy::nonminimal_bool)]
     fn test_level_filer() {
         assert!(!(Level::TRACE <= Level::DEBUG));
         assert!(Level::DEBUG <= Level::DEBUG);
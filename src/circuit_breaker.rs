@@ -0,0 +1,112 @@
+use backon::{BackoffBuilder, ExponentialBackoff, ExponentialBuilder};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::config::BreakerConfig;
+
+/// Exponential backoff between consecutive failures, which opens after too many of them
+/// and only allows one attempt per cooldown until a success closes it again
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: &'static str,
+    threshold: usize,
+    max_delay: Duration,
+    cooldown: Duration,
+    failures: usize,
+    backoff: Option<ExponentialBackoff>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &'static str, config: &BreakerConfig) -> Self {
+        Self {
+            name,
+            threshold: config.threshold.max(1),
+            max_delay: Duration::from_secs(config.max_delay_secs),
+            cooldown: Duration::from_secs(config.cooldown_secs),
+            failures: 0,
+            backoff: None,
+        }
+    }
+
+    #[inline]
+    pub fn is_open(&self) -> bool {
+        self.failures >= self.threshold
+    }
+
+    pub fn success(&mut self) {
+        if self.is_open() {
+            info!(name = self.name, "circuit breaker closed");
+        }
+        self.failures = 0;
+        self.backoff = None;
+    }
+
+    /// Record a failure and return how long to wait before the next attempt
+    pub fn failure(&mut self) -> Duration {
+        self.failures += 1;
+
+        if self.failures == self.threshold {
+            warn!(
+                name = self.name,
+                failures = self.failures,
+                "circuit breaker open"
+            );
+            sentry::capture_message(
+                &format!("Circuit breaker for {} open", self.name),
+                sentry::Level::Error,
+            );
+        }
+
+        if self.is_open() {
+            return self.cooldown;
+        }
+
+        let max_delay = self.max_delay;
+        self.backoff
+            .get_or_insert_with(|| {
+                ExponentialBuilder::default()
+                    .with_jitter()
+                    .with_max_delay(max_delay)
+                    .with_max_times(usize::MAX)
+                    .build()
+            })
+            .next()
+            .unwrap_or(max_delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let cooldown = Duration::from_secs(100);
+        let mut breaker = CircuitBreaker::new(
+            "test",
+            &BreakerConfig {
+                threshold: 3,
+                max_delay_secs: 10,
+                cooldown_secs: 100,
+            },
+        );
+
+        for _ in 0..2 {
+            let delay = breaker.failure();
+            assert!(
+                delay < cooldown,
+                "backoff {delay:?} should be under the cooldown"
+            );
+            assert!(!breaker.is_open());
+        }
+
+        assert_eq!(breaker.failure(), cooldown);
+        assert!(breaker.is_open());
+        // half-open attempt fails again
+        assert_eq!(breaker.failure(), cooldown);
+
+        breaker.success();
+        assert!(!breaker.is_open());
+        assert!(breaker.failure() < cooldown);
+    }
+}
@@ -0,0 +1,184 @@
+use crate::errors::ProcessFileError;
+use aws_sdk_s3::{error::SdkError, Client};
+use aws_smithy_types::{byte_stream::ByteStream, DateTime};
+use once_cell::sync::Lazy;
+use std::time::Duration;
+use tracing::{debug, error, info, instrument, warn};
+
+/// Prefix of the claim objects in the bucket they guard, archives under it are never deployed
+pub const CLAIM_PREFIX: &str = ".deploy-claims/";
+
+static WORKER_ID: Lazy<String> = Lazy::new(|| uuid::Uuid::new_v4().to_string());
+
+/// Exclusive right to deploy a release, held until released or expired
+///
+/// A claim is an object `{CLAIM_PREFIX}{id}/{generation}` created with `If-None-Match: *`, so only
+/// one worker can create each generation. A claim older than the TTL is considered stale and
+/// can be taken over by creating the next generation.
+#[derive(Debug)]
+pub struct Claim<'a> {
+    client: &'a Client,
+    bucket: String,
+    prefix: String,
+    generation: u32,
+}
+
+#[derive(Debug)]
+pub enum ClaimState<'a> {
+    Claimed(Claim<'a>),
+    /// Claimed by another worker, which may still be deploying
+    Held,
+}
+
+#[instrument(err, skip(client))]
+pub async fn claim<'a>(
+    client: &'a Client,
+    bucket: &str,
+    id: &str,
+    ttl: Duration,
+) -> Result<ClaimState<'a>, ProcessFileError> {
+    let prefix = format!("{CLAIM_PREFIX}{id}/");
+
+    let generation = match latest_generation(client, bucket, &prefix).await? {
+        None => 0,
+        Some((generation, last_modified)) => {
+            let age = DateTime::from(std::time::SystemTime::now()).secs() - last_modified.secs();
+            if age < ttl.as_secs() as i64 {
+                info!(generation, age, "claimed by another worker");
+                return Ok(ClaimState::Held);
+            }
+            warn!(generation, age, "take over stale claim");
+            generation + 1
+        }
+    };
+
+    let res = client
+        .put_object()
+        .bucket(bucket)
+        .key(generation_key(&prefix, generation))
+        .if_none_match("*")
+        .body(ByteStream::from(WORKER_ID.as_bytes().to_vec()))
+        .send()
+        .await;
+
+    match res {
+        Ok(_) => {
+            debug!(generation, "claimed");
+            Ok(ClaimState::Claimed(Claim {
+                client,
+                bucket: bucket.to_owned(),
+                prefix,
+                generation,
+            }))
+        }
+        // 412 when the generation exists, 409 when another worker is creating it at the same time
+        Err(SdkError::ServiceError(err)) if matches!(err.raw().status().as_u16(), 409 | 412) => {
+            info!(generation, "claimed by another worker concurrently");
+            Ok(ClaimState::Held)
+        }
+        Err(err) => {
+            error!(?err, "Fail to claim {bucket}/{prefix}");
+            Err(ProcessFileError::S3Error)
+        }
+    }
+}
+
+impl Claim<'_> {
+    /// Remove the claim and the stale generations it took over
+    #[instrument(skip(self), fields(prefix = self.prefix, generation = self.generation))]
+    pub async fn release(self) {
+        for generation in 0..=self.generation {
+            if let Err(err) = self
+                .client
+                .delete_object()
+                .bucket(&self.bucket)
+                .key(generation_key(&self.prefix, generation))
+                .send()
+                .await
+            {
+                error!(?err, generation, "Fail to release claim");
+                sentry::capture_error(&err);
+            }
+        }
+    }
+}
+
+async fn latest_generation(
+    client: &Client,
+    bucket: &str,
+    prefix: &str,
+) -> Result<Option<(u32, DateTime)>, ProcessFileError> {
+    let output = client
+        .list_objects_v2()
+        .bucket(bucket)
+        .prefix(prefix)
+        .send()
+        .await
+        .map_err(|err| {
+            error!(?err, "Fail to list claims {bucket}/{prefix}");
+            ProcessFileError::S3Error
+        })?;
+
+    let latest = output
+        .contents()
+        .iter()
+        .filter_map(|object| {
+            let generation = object.key()?.strip_prefix(prefix)?.parse::<u32>().ok()?;
+            Some((generation, *object.last_modified()?))
+        })
+        .max_by_key(|(generation, _)| *generation);
+
+    Ok(latest)
+}
+
+#[inline]
+fn generation_key(prefix: &str, generation: u32) -> String {
+    // zero padded so generations sort by key
+    format!("{prefix}{generation:010}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_config::BehaviorVersion;
+
+    const BUCKET: &str = "deploy-claims";
+
+    #[tokio::test]
+    #[ignore] // default disable as it needs a local S3, e.g. `localstack` with a `deploy-claims` bucket
+    async fn test_claim_is_exclusive() {
+        let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+        let config = aws_sdk_s3::config::Builder::from(&config)
+            .endpoint_url("http://localhost:4566/")
+            .force_path_style(true)
+            .build();
+        let client = Client::from_conf(config);
+        let id = uuid::Uuid::new_v4().to_string();
+        let ttl = Duration::from_secs(60);
+
+        let ClaimState::Claimed(claim) = super::claim(&client, BUCKET, &id, ttl).await.unwrap()
+        else {
+            panic!("first claim should succeed");
+        };
+        assert!(matches!(
+            super::claim(&client, BUCKET, &id, ttl).await.unwrap(),
+            ClaimState::Held
+        ));
+
+        claim.release().await;
+        let ClaimState::Claimed(_stale) = super::claim(&client, BUCKET, &id, ttl).await.unwrap()
+        else {
+            panic!("claim should succeed after release");
+        };
+
+        // a zero TTL makes the previous claim stale
+        let ClaimState::Claimed(claim) = super::claim(&client, BUCKET, &id, Duration::ZERO)
+            .await
+            .unwrap()
+        else {
+            panic!("stale claim should be taken over");
+        };
+        assert_eq!(claim.generation, 1);
+        claim.release().await;
+    }
+}
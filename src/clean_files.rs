@@ -15,22 +15,21 @@ impl<C: jwalk::ClientState> DirEntryLike for DirEntry<C> {
 
     fn metadata(&self) -> std::io::Result<std::fs::Metadata> {
         self.metadata().map_err(|err| {
-            err.into_io_error().unwrap_or_else(|| {
-                std::io::Error::new(std::io::ErrorKind::Other, anyhow::anyhow!("unknown error"))
-            })
+            err.into_io_error()
+                .unwrap_or_else(|| std::io::Error::other(anyhow::anyhow!("unknown error")))
         })
     }
 }
 
 fn is_over_25mb(path: &dyn DirEntryLike) -> bool {
     path.metadata()
-        .map_or(false, |meta| meta.len() > 25 * 1024 * 1024)
+        .is_ok_and(|meta| meta.len() > 25 * 1024 * 1024)
 }
 
 fn is_path_ends_with(path: &dyn DirEntryLike, suffix: &str) -> bool {
     path.file_name()
         .to_str()
-        .map_or(false, |file_name| file_name.ends_with(suffix))
+        .is_some_and(|file_name| file_name.ends_with(suffix))
 }
 
 pub(crate) trait CleanRule: Send + Sync {
@@ -115,5 +114,7 @@ pub(crate) fn clean_unused_files(root: &Path) -> FileSummary {
         ignored,
         removed_fail,
         removed_success,
+        upload: Default::default(),
+        deployment: None,
     }
 }
@@ -0,0 +1,989 @@
+use crate::{
+    environment::EnvironmentRegistry, extract::ExtractLimits, header_policy::HeaderPolicy,
+    pages::PagesBackend, signature::KeySet,
+};
+use serde_derive::Deserialize;
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Path of the optional TOML config file, env vars take precedence over it
+pub const CONFIG_PATH_ENV: &str = "DEPLOYER_CONFIG";
+/// Smallest part accepted by S3 multipart uploads
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+/// Settings of the deployer, loaded once at startup by [`Config::load`]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub sentry_dsn: String,
+    pub sentry_cron_check_url: String,
+    /// Maximum number of records in one S3 event processed at the same time
+    pub record_concurrency: usize,
+    /// Time after which a deploy claim is considered stale
+    pub claim_ttl_secs: u64,
+    /// Keys trusted to sign deploy meta, see [`KeySet::parse`]
+    pub deploy_meta_keys: KeySet,
+    /// Accept unsigned deploy meta while `deploy_meta_keys` is empty
+    pub allow_unsigned_meta: bool,
+    /// Send the Pages deployment id and URL with the done release update, only once the API
+    /// accepts `deploymentId` and `deploymentUrl` in `UpdateReleaseInput`
+    pub report_deployment: bool,
+    pub environments: EnvironmentRegistry,
+    pub wrangler: WranglerConfig,
+    pub pages: PagesConfig,
+    pub retry: RetryConfig,
+    pub r2: R2Config,
+    pub upload: UploadConfig,
+    pub gc: GcConfig,
+    pub s3_static: S3StaticConfig,
+    pub local_dir: LocalDirConfig,
+    pub extract: ExtractLimits,
+    pub queue: QueueConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WranglerConfig {
+    /// Working dir of wrangler, archives are extracted under it
+    pub root: PathBuf,
+    pub timeout_secs: u64,
+    pub static_timeout_secs: u64,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PagesConfig {
+    pub backend: PagesBackend,
+    pub api_base: String,
+    pub api_token: String,
+    /// Used when the environment of the client has no Cloudflare account
+    pub account_id: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Number of retries after the first attempt
+    pub limit: u32,
+    /// Delay before the first retry, doubled by default for each of the next ones
+    pub delay_secs: u64,
+    pub max_delay_secs: u64,
+    pub factor: f32,
+    /// Randomize the delays, so failed uploads don't retry all at once
+    pub jitter: bool,
+    /// No retry starts after this time since the first attempt
+    pub max_elapsed_secs: u64,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct R2Config {
+    pub bucket: String,
+    /// Cloudflare account of the bucket, used to derive the endpoint when `endpoint_url` is empty
+    pub account_id: String,
+    /// Explicit endpoint, e.g. a MinIO or `localstack` instance in integration tests
+    pub endpoint_url: String,
+    pub region: String,
+    /// Address the bucket in the path instead of the host, required by most S3 stand-ins
+    pub force_path_style: bool,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+/// Uploads of the `_nuxt` assets to R2, and of the sites to the `s3_static` target
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadConfig {
+    /// Files uploaded at the same time
+    pub concurrency: usize,
+    /// Files from this size are uploaded in parts
+    pub multipart_threshold: u64,
+    /// Size of each part, S3 requires at least 5 MiB except for the last one
+    pub part_size: u64,
+    /// Skip files whose ETag in R2 matches the local content
+    pub sync: bool,
+    /// Files allowed to fail after the retry before the deploy fails
+    pub max_failures: usize,
+    pub headers: HeaderPolicy,
+}
+
+/// Removal of the `_nuxt` assets no longer used by the latest releases
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GcConfig {
+    pub enabled: bool,
+    /// Releases whose assets are kept, including the one just deployed
+    pub keep_releases: usize,
+    /// Assets younger than this are always kept, whatever the releases
+    pub min_age_secs: u64,
+    /// Only log the stale assets
+    pub dry_run: bool,
+}
+
+/// Bucket of the `s3_static` deploy target, served as a static website
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3StaticConfig {
+    /// Bucket of each site, `{client_id}` is replaced with the lowercase client id, empty disables
+    /// the target
+    pub bucket: String,
+    /// Empty for AWS S3
+    pub endpoint_url: String,
+    pub region: String,
+    pub force_path_style: bool,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Served for the requests to a directory
+    pub index_document: String,
+    /// Served for missing keys when the site has it
+    pub error_document: String,
+}
+
+/// Root of the `local_dir` deploy target, empty disables the target
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocalDirConfig {
+    pub root: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    pub url: String,
+    pub workers: usize,
+    pub wait_time_seconds: i32,
+    /// Receives of a message before it is dead-lettered
+    pub max_receive_count: u32,
+    pub dead_letter_queue_url: Option<String>,
+    /// Delay before retrying a release claimed by another worker, roughly the time of a deploy
+    pub defer_secs: i32,
+    /// Time to wait for in-flight deployments on shutdown before cancelling them
+    pub shutdown_timeout_secs: u64,
+    /// Visibility timeout before the heartbeat starts extending it
+    pub heartbeat_timeout_secs: i32,
+    /// Use the SQS of `localstack` on `localhost:4566`
+    pub localstack: bool,
+    /// Slows down receiving while SQS keeps failing
+    pub breaker: BreakerConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BreakerConfig {
+    /// Consecutive failures which open the breaker
+    pub threshold: usize,
+    /// Longest backoff between failures while the breaker is closed
+    pub max_delay_secs: u64,
+    /// Delay between attempts while the breaker is open
+    pub cooldown_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            sentry_dsn: String::new(),
+            sentry_cron_check_url: String::new(),
+            record_concurrency: 4,
+            claim_ttl_secs: 60 * 90, // outlasts the last retry of a static wrangler deploy
+            deploy_meta_keys: KeySet::default(),
+            allow_unsigned_meta: false,
+            report_deployment: false,
+            environments: EnvironmentRegistry::default(),
+            wrangler: WranglerConfig::default(),
+            pages: PagesConfig::default(),
+            retry: RetryConfig::default(),
+            r2: R2Config::default(),
+            upload: UploadConfig::default(),
+            gc: GcConfig::default(),
+            s3_static: S3StaticConfig::default(),
+            local_dir: LocalDirConfig::default(),
+            extract: ExtractLimits::default(),
+            queue: QueueConfig::default(),
+        }
+    }
+}
+
+impl Default for WranglerConfig {
+    fn default() -> Self {
+        Self {
+            root: PathBuf::from("/tmp/wrangler_root"),
+            timeout_secs: 60 * 20,
+            static_timeout_secs: 60 * 60,
+        }
+    }
+}
+
+impl Default for PagesConfig {
+    fn default() -> Self {
+        Self {
+            backend: PagesBackend::default(),
+            api_base: "https://api.cloudflare.com/client/v4".to_owned(),
+            api_token: String::new(),
+            account_id: String::new(),
+        }
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            limit: 2,
+            delay_secs: 2,
+            max_delay_secs: 30,
+            factor: 2.0,
+            jitter: true,
+            // a wrangler attempt starting this late still ends before the claim expires
+            max_elapsed_secs: 60 * 20,
+        }
+    }
+}
+
+impl Default for R2Config {
+    fn default() -> Self {
+        Self {
+            bucket: "storipress".to_owned(),
+            account_id: String::new(),
+            endpoint_url: String::new(),
+            region: "auto".to_owned(),
+            force_path_style: false,
+            access_key: String::new(),
+            secret_key: String::new(),
+        }
+    }
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 8,
+            multipart_threshold: 16 * 1024 * 1024,
+            part_size: 8 * 1024 * 1024,
+            sync: true,
+            max_failures: 0,
+            headers: HeaderPolicy::default(),
+        }
+    }
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            keep_releases: 5,
+            min_age_secs: 60 * 60 * 24 * 3,
+            dry_run: false,
+        }
+    }
+}
+
+impl Default for S3StaticConfig {
+    fn default() -> Self {
+        Self {
+            bucket: String::new(),
+            endpoint_url: String::new(),
+            region: "us-east-1".to_owned(),
+            force_path_style: false,
+            access_key: String::new(),
+            secret_key: String::new(),
+            index_document: "index.html".to_owned(),
+            error_document: "404.html".to_owned(),
+        }
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            workers: 2,
+            wait_time_seconds: 20,
+            max_receive_count: 5,
+            dead_letter_queue_url: None,
+            defer_secs: 60 * 5,
+            shutdown_timeout_secs: 20,
+            heartbeat_timeout_secs: 240,
+            localstack: false,
+            breaker: BreakerConfig::default(),
+        }
+    }
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 5,
+            max_delay_secs: 30,
+            cooldown_secs: 60 * 5,
+        }
+    }
+}
+
+// keep the credentials out of the logs
+impl fmt::Debug for PagesConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PagesConfig")
+            .field("backend", &self.backend)
+            .field("api_base", &self.api_base)
+            .field("account_id", &self.account_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for R2Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("R2Config")
+            .field("bucket", &self.bucket)
+            .field("account_id", &self.account_id)
+            .field("endpoint_url", &self.endpoint_url)
+            .field("region", &self.region)
+            .field("force_path_style", &self.force_path_style)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for S3StaticConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3StaticConfig")
+            .field("bucket", &self.bucket)
+            .field("endpoint_url", &self.endpoint_url)
+            .field("region", &self.region)
+            .field("force_path_style", &self.force_path_style)
+            .field("index_document", &self.index_document)
+            .field("error_document", &self.error_document)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("fail to read config file {}: {source}", .path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid config file {}: {source}", .path.display())]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("invalid env {name}: {reason}")]
+    Env { name: &'static str, reason: String },
+    #[error("invalid {field}: {reason}")]
+    Invalid {
+        field: &'static str,
+        reason: &'static str,
+    },
+}
+
+/// Every problem found in the config
+#[derive(Debug, thiserror::Error)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid config:")?;
+        for err in &self.0 {
+            writeln!(f, "  - {err}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Load the TOML file in `DEPLOYER_CONFIG` if any, apply the env vars, then validate the result
+    pub fn load() -> Result<Self, ConfigErrors> {
+        let path = env::var_os(CONFIG_PATH_ENV).map(PathBuf::from);
+        Self::load_from(path.as_deref(), |name| env::var(name).ok())
+    }
+
+    pub fn load_from(
+        path: Option<&Path>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigErrors> {
+        let mut config = match path {
+            Some(path) => {
+                let content = fs::read_to_string(path).map_err(|source| {
+                    ConfigErrors(vec![ConfigError::Read {
+                        path: path.to_owned(),
+                        source,
+                    }])
+                })?;
+                toml::from_str(&content).map_err(|source| {
+                    ConfigErrors(vec![ConfigError::Parse {
+                        path: path.to_owned(),
+                        source,
+                    }])
+                })?
+            }
+            None => Config::default(),
+        };
+
+        let mut errors = Vec::new();
+        config.apply_env(&env, &mut errors);
+        config.validate(&mut errors);
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigErrors(errors))
+        }
+    }
+
+    fn apply_env(&mut self, env: &impl Fn(&str) -> Option<String>, errors: &mut Vec<ConfigError>) {
+        let mut vars = EnvVars { env, errors };
+        vars.set("SENTRY_DSN", &mut self.sentry_dsn);
+        vars.set("SENTRY_CRON_CHECK_URL", &mut self.sentry_cron_check_url);
+        vars.set("RECORD_CONCURRENCY", &mut self.record_concurrency);
+        vars.set("CLAIM_TTL_SECS", &mut self.claim_ttl_secs);
+        vars.set("DEPLOY_META_KEYS", &mut self.deploy_meta_keys);
+        vars.set("ALLOW_UNSIGNED_META", &mut self.allow_unsigned_meta);
+        vars.set("REPORT_DEPLOYMENT", &mut self.report_deployment);
+        vars.set("DEPLOY_ENVIRONMENTS", &mut self.environments);
+
+        vars.set("WRANGLER_ROOT", &mut self.wrangler.root);
+        vars.set("WRANGLER_TIMEOUT_SECS", &mut self.wrangler.timeout_secs);
+        vars.set(
+            "WRANGLER_STATIC_TIMEOUT_SECS",
+            &mut self.wrangler.static_timeout_secs,
+        );
+
+        vars.set("PAGES_BACKEND", &mut self.pages.backend);
+        vars.set("PAGES_API_BASE", &mut self.pages.api_base);
+        vars.set("CLOUDFLARE_API_TOKEN", &mut self.pages.api_token);
+        vars.set("CLOUDFLARE_ACCOUNT_ID", &mut self.pages.account_id);
+
+        vars.set("RETRY_LIMIT", &mut self.retry.limit);
+        vars.set("RETRY_DELAY_SECS", &mut self.retry.delay_secs);
+        vars.set("RETRY_MAX_DELAY_SECS", &mut self.retry.max_delay_secs);
+        vars.set("RETRY_FACTOR", &mut self.retry.factor);
+        vars.set("RETRY_JITTER", &mut self.retry.jitter);
+        vars.set("RETRY_MAX_ELAPSED_SECS", &mut self.retry.max_elapsed_secs);
+
+        vars.set("R2_BUCKET", &mut self.r2.bucket);
+        vars.set("R2_ACCOUNT_ID", &mut self.r2.account_id);
+        vars.set("R2_ENDPOINT_URL", &mut self.r2.endpoint_url);
+        vars.set("R2_REGION", &mut self.r2.region);
+        vars.set("R2_FORCE_PATH_STYLE", &mut self.r2.force_path_style);
+        vars.set("R2_ACCESS_KEY", &mut self.r2.access_key);
+        vars.set("R2_SECRET_KEY", &mut self.r2.secret_key);
+
+        vars.set("UPLOAD_CONCURRENCY", &mut self.upload.concurrency);
+        vars.set(
+            "UPLOAD_MULTIPART_THRESHOLD",
+            &mut self.upload.multipart_threshold,
+        );
+        vars.set("UPLOAD_PART_SIZE", &mut self.upload.part_size);
+        vars.set("UPLOAD_SYNC", &mut self.upload.sync);
+        vars.set("UPLOAD_MAX_FAILURES", &mut self.upload.max_failures);
+        vars.set(
+            "CACHE_CONTROL_IMMUTABLE",
+            &mut self.upload.headers.immutable_cache_control,
+        );
+        vars.set("CACHE_CONTROL", &mut self.upload.headers.cache_control);
+        vars.set_list("PRECOMPRESS", &mut self.upload.headers.precompress);
+
+        vars.set("GC_ENABLED", &mut self.gc.enabled);
+        vars.set("GC_KEEP_RELEASES", &mut self.gc.keep_releases);
+        vars.set("GC_MIN_AGE_SECS", &mut self.gc.min_age_secs);
+        vars.set("GC_DRY_RUN", &mut self.gc.dry_run);
+
+        vars.set("S3_STATIC_BUCKET", &mut self.s3_static.bucket);
+        vars.set("S3_STATIC_ENDPOINT_URL", &mut self.s3_static.endpoint_url);
+        vars.set("S3_STATIC_REGION", &mut self.s3_static.region);
+        vars.set(
+            "S3_STATIC_FORCE_PATH_STYLE",
+            &mut self.s3_static.force_path_style,
+        );
+        vars.set("S3_STATIC_ACCESS_KEY", &mut self.s3_static.access_key);
+        vars.set("S3_STATIC_SECRET_KEY", &mut self.s3_static.secret_key);
+        vars.set(
+            "S3_STATIC_INDEX_DOCUMENT",
+            &mut self.s3_static.index_document,
+        );
+        vars.set(
+            "S3_STATIC_ERROR_DOCUMENT",
+            &mut self.s3_static.error_document,
+        );
+
+        vars.set("LOCAL_DIR_ROOT", &mut self.local_dir.root);
+
+        vars.set("EXTRACT_MAX_BYTES", &mut self.extract.max_bytes);
+        vars.set("EXTRACT_MAX_ENTRIES", &mut self.extract.max_entries);
+
+        vars.set("AWS_QUEUE_URL", &mut self.queue.url);
+        vars.set("DEPLOY_WORKERS", &mut self.queue.workers);
+        vars.set("SQS_WAIT_TIME_SECONDS", &mut self.queue.wait_time_seconds);
+        vars.set("MAX_RECEIVE_COUNT", &mut self.queue.max_receive_count);
+        vars.set_option(
+            "DEAD_LETTER_QUEUE_URL",
+            &mut self.queue.dead_letter_queue_url,
+        );
+        vars.set("DEFER_SECS", &mut self.queue.defer_secs);
+        vars.set(
+            "SHUTDOWN_TIMEOUT_SECS",
+            &mut self.queue.shutdown_timeout_secs,
+        );
+        vars.set(
+            "HEARTBEAT_TIMEOUT_SECS",
+            &mut self.queue.heartbeat_timeout_secs,
+        );
+        vars.set("LOCALSTACK", &mut self.queue.localstack);
+        vars.set("SQS_BREAKER_THRESHOLD", &mut self.queue.breaker.threshold);
+        vars.set(
+            "SQS_BREAKER_MAX_DELAY_SECS",
+            &mut self.queue.breaker.max_delay_secs,
+        );
+        vars.set(
+            "SQS_BREAKER_COOLDOWN_SECS",
+            &mut self.queue.breaker.cooldown_secs,
+        );
+    }
+
+    fn validate(&self, errors: &mut Vec<ConfigError>) {
+        let mut check = |valid: bool, field: &'static str, reason: &'static str| {
+            if !valid {
+                errors.push(ConfigError::Invalid { field, reason });
+            }
+        };
+
+        check(
+            self.record_concurrency > 0,
+            "record_concurrency",
+            "must be positive",
+        );
+        // the last attempt may start at max_elapsed and run until the wrangler timeout
+        let wrangler_timeout_secs = self
+            .wrangler
+            .timeout_secs
+            .max(self.wrangler.static_timeout_secs);
+        check(
+            self.retry
+                .max_elapsed_secs
+                .saturating_add(wrangler_timeout_secs)
+                < self.claim_ttl_secs,
+            "claim_ttl_secs",
+            "must be longer than retry.max_elapsed_secs plus the wrangler timeouts",
+        );
+        check(
+            self.wrangler.root.is_absolute(),
+            "wrangler.root",
+            "must be an absolute path",
+        );
+        check(
+            self.wrangler.timeout_secs > 0,
+            "wrangler.timeout_secs",
+            "must be positive",
+        );
+        check(
+            self.wrangler.static_timeout_secs > 0,
+            "wrangler.static_timeout_secs",
+            "must be positive",
+        );
+        check(
+            self.pages.backend != PagesBackend::DirectUpload || !self.pages.api_token.is_empty(),
+            "pages.api_token",
+            "is required by direct_upload, set CLOUDFLARE_API_TOKEN",
+        );
+        check(
+            self.retry.factor >= 1.0,
+            "retry.factor",
+            "must be at least 1",
+        );
+        check(
+            self.retry.max_delay_secs >= self.retry.delay_secs,
+            "retry.max_delay_secs",
+            "must be at least retry.delay_secs",
+        );
+        // static sites and the other targets don't use R2, it is checked once any of it is set
+        if self.r2.is_set() {
+            check(!self.r2.bucket.is_empty(), "r2.bucket", "is required");
+            match self.r2.endpoint() {
+                Some(endpoint) => check(
+                    reqwest::Url::parse(&endpoint)
+                        .is_ok_and(|url| matches!(url.scheme(), "http" | "https")),
+                    "r2.endpoint_url",
+                    "must be an http(s) URL",
+                ),
+                None => check(
+                    false,
+                    "r2.endpoint_url",
+                    "is required, set R2_ENDPOINT_URL or R2_ACCOUNT_ID",
+                ),
+            }
+            check(!self.r2.region.is_empty(), "r2.region", "is required");
+            check(
+                !self.r2.access_key.is_empty(),
+                "r2.access_key",
+                "is required, set R2_ACCESS_KEY",
+            );
+            check(
+                !self.r2.secret_key.is_empty(),
+                "r2.secret_key",
+                "is required, set R2_SECRET_KEY",
+            );
+        }
+        check(
+            self.upload.concurrency > 0,
+            "upload.concurrency",
+            "must be positive",
+        );
+        check(
+            self.upload.part_size >= MIN_PART_SIZE,
+            "upload.part_size",
+            "must be at least 5 MiB",
+        );
+        check(
+            self.upload.multipart_threshold >= self.upload.part_size,
+            "upload.multipart_threshold",
+            "must be at least upload.part_size",
+        );
+        check(
+            self.gc.keep_releases > 0,
+            "gc.keep_releases",
+            "must be positive",
+        );
+        if !self.s3_static.bucket.is_empty() {
+            check(
+                self.s3_static.endpoint_url.is_empty()
+                    || reqwest::Url::parse(&self.s3_static.endpoint_url)
+                        .is_ok_and(|url| matches!(url.scheme(), "http" | "https")),
+                "s3_static.endpoint_url",
+                "must be an http(s) URL",
+            );
+            check(
+                !self.s3_static.region.is_empty(),
+                "s3_static.region",
+                "is required",
+            );
+            check(
+                !self.s3_static.access_key.is_empty() && !self.s3_static.secret_key.is_empty(),
+                "s3_static.access_key",
+                "is required with s3_static.secret_key, set S3_STATIC_ACCESS_KEY and S3_STATIC_SECRET_KEY",
+            );
+            check(
+                !self.s3_static.index_document.is_empty(),
+                "s3_static.index_document",
+                "is required",
+            );
+        }
+        check(
+            self.local_dir.root.as_os_str().is_empty() || self.local_dir.root.is_absolute(),
+            "local_dir.root",
+            "must be an absolute path",
+        );
+        check(
+            self.extract.max_bytes > 0,
+            "extract.max_bytes",
+            "must be positive",
+        );
+        check(
+            self.extract.max_entries > 0,
+            "extract.max_entries",
+            "must be positive",
+        );
+        check(self.queue.workers > 0, "queue.workers", "must be positive");
+        check(
+            (0..=20).contains(&self.queue.wait_time_seconds),
+            "queue.wait_time_seconds",
+            "must be between 0 and 20",
+        );
+        check(
+            self.queue.max_receive_count > 0,
+            "queue.max_receive_count",
+            "must be positive",
+        );
+        check(
+            (1..=43200).contains(&self.queue.defer_secs),
+            "queue.defer_secs",
+            "must be between 1 and 43200",
+        );
+        check(
+            self.queue.heartbeat_timeout_secs > 10,
+            "queue.heartbeat_timeout_secs",
+            "must be more than 10",
+        );
+        check(
+            self.queue.breaker.threshold > 0,
+            "queue.breaker.threshold",
+            "must be positive",
+        );
+    }
+
+    #[inline]
+    pub fn claim_ttl(&self) -> Duration {
+        Duration::from_secs(self.claim_ttl_secs)
+    }
+}
+
+impl R2Config {
+    /// Whether R2 is configured at all, the default bucket and region don't count
+    pub fn is_set(&self) -> bool {
+        self.endpoint().is_some() || !self.access_key.is_empty() || !self.secret_key.is_empty()
+    }
+
+    /// The explicit endpoint, otherwise the one of the account
+    pub fn endpoint(&self) -> Option<String> {
+        if !self.endpoint_url.is_empty() {
+            Some(self.endpoint_url.clone())
+        } else if !self.account_id.is_empty() {
+            Some(format!(
+                "https://{}.r2.cloudflarestorage.com",
+                self.account_id
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+impl QueueConfig {
+    #[inline]
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+}
+
+struct EnvVars<'a, E> {
+    env: &'a E,
+    errors: &'a mut Vec<ConfigError>,
+}
+
+impl<E: Fn(&str) -> Option<String>> EnvVars<'_, E> {
+    fn set<T>(&mut self, name: &'static str, field: &mut T)
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        if let Some(value) = (self.env)(name).and_then(|value| self.parse(name, &value)) {
+            *field = value;
+        }
+    }
+
+    /// Same as [`EnvVars::set`], but an empty value unsets the field
+    fn set_option<T>(&mut self, name: &'static str, field: &mut Option<T>)
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        match (self.env)(name) {
+            Some(value) if value.is_empty() => *field = None,
+            Some(value) => {
+                if let Some(value) = self.parse(name, &value) {
+                    *field = Some(value);
+                }
+            }
+            None => (),
+        }
+    }
+
+    /// Comma separated values, an empty value clears the list
+    fn set_list<T>(&mut self, name: &'static str, field: &mut Vec<T>)
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        if let Some(value) = (self.env)(name) {
+            let values = value
+                .split(',')
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(|value| self.parse(name, value))
+                .collect::<Option<Vec<_>>>();
+            if let Some(values) = values {
+                *field = values;
+            }
+        }
+    }
+
+    fn parse<T>(&mut self, name: &'static str, value: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        value
+            .parse()
+            .map_err(|err: T::Err| {
+                self.errors.push(ConfigError::Env {
+                    name,
+                    reason: err.to_string(),
+                })
+            })
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header_policy::Encoding;
+    use std::{collections::HashMap, io::Write};
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+        move |name| vars.get(name).cloned()
+    }
+
+    const R2_ENV: &[(&str, &str)] = &[
+        (
+            "R2_ENDPOINT_URL",
+            "https://account.r2.cloudflarestorage.com",
+        ),
+        ("R2_ACCESS_KEY", "access"),
+        ("R2_SECRET_KEY", "secret"),
+    ];
+
+    #[test]
+    fn test_load_file_and_env() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"
+            record_concurrency = 8
+
+            [wrangler]
+            root = "/var/deployer"
+
+            [queue]
+            workers = 4
+            dead_letter_queue_url = "https://sqs/dlq"
+
+            [queue.breaker]
+            threshold = 3
+
+            [upload.headers.overrides]
+            json = "no-cache"
+
+            [[environments]]
+            name = "local"
+            client_prefix = "L"
+            api_base = "http://localhost:8080"
+            "#
+        )
+        .unwrap();
+
+        let vars = [
+            R2_ENV,
+            &[
+                ("DEPLOY_WORKERS", "6"),
+                ("DEAD_LETTER_QUEUE_URL", ""),
+                ("PRECOMPRESS", "br, gzip"),
+                ("SQS_BREAKER_COOLDOWN_SECS", "60"),
+            ],
+        ]
+        .concat();
+        let config = Config::load_from(Some(file.path()), env(&vars)).unwrap();
+
+        assert_eq!(config.record_concurrency, 8);
+        assert_eq!(config.wrangler.root, Path::new("/var/deployer"));
+        assert_eq!(config.wrangler.timeout_secs, 60 * 20);
+        assert_eq!(config.queue.workers, 6);
+        assert_eq!(config.queue.dead_letter_queue_url, None);
+        assert_eq!(config.queue.breaker.threshold, 3);
+        assert_eq!(config.queue.breaker.cooldown_secs, 60);
+        assert_eq!(config.r2.bucket, "storipress");
+        assert_eq!(
+            config.upload.headers.precompress,
+            [Encoding::Br, Encoding::Gzip]
+        );
+        assert_eq!(
+            config
+                .upload
+                .headers
+                .cache_control("P1/_nuxt/builds/latest.json"),
+            "no-cache"
+        );
+        assert_eq!(
+            config.environments.resolve("L1").unwrap().api_base,
+            "http://localhost:8080"
+        );
+        assert_eq!(
+            config.environments.resolve("P1").unwrap().name,
+            "production"
+        );
+    }
+
+    #[test]
+    fn test_r2_endpoint() {
+        let config = Config::load_from(None, env(&[])).unwrap();
+        assert!(!config.r2.is_set());
+
+        let vars = [
+            ("R2_ACCOUNT_ID", "account"),
+            ("R2_ACCESS_KEY", "access"),
+            ("R2_SECRET_KEY", "secret"),
+        ];
+        let config = Config::load_from(None, env(&vars)).unwrap();
+        assert_eq!(
+            config.r2.endpoint().as_deref(),
+            Some("https://account.r2.cloudflarestorage.com")
+        );
+        assert_eq!(config.r2.region, "auto");
+        assert!(!config.r2.force_path_style);
+
+        let vars = [
+            R2_ENV,
+            &[
+                ("R2_ENDPOINT_URL", "http://localhost:9000"),
+                ("R2_REGION", "us-east-1"),
+                ("R2_FORCE_PATH_STYLE", "true"),
+            ],
+        ]
+        .concat();
+        let config = Config::load_from(None, env(&vars)).unwrap();
+        assert_eq!(
+            config.r2.endpoint().as_deref(),
+            Some("http://localhost:9000")
+        );
+        assert_eq!(config.r2.region, "us-east-1");
+        assert!(config.r2.force_path_style);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let vars = [
+            ("DEPLOY_WORKERS", "many"),
+            ("SQS_WAIT_TIME_SECONDS", "30"),
+            ("R2_ACCESS_KEY", "access"),
+        ];
+        let errors = Config::load_from(None, env(&vars)).unwrap_err();
+        let errors = errors.0.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+        assert_eq!(
+            errors,
+            [
+                "invalid env DEPLOY_WORKERS: invalid digit found in string",
+                "invalid r2.endpoint_url: is required, set R2_ENDPOINT_URL or R2_ACCOUNT_ID",
+                "invalid r2.secret_key: is required, set R2_SECRET_KEY",
+                "invalid queue.wait_time_seconds: must be between 0 and 20",
+            ]
+        );
+
+        let vars = [R2_ENV, &[("R2_ENDPOINT_URL", "localhost:9000")]].concat();
+        let errors = Config::load_from(None, env(&vars)).unwrap_err();
+        assert_eq!(
+            errors.0[0].to_string(),
+            "invalid r2.endpoint_url: must be an http(s) URL"
+        );
+
+        // a retry of the static wrangler deploy would outlive the claim
+        let vars = [("RETRY_MAX_ELAPSED_SECS", "3600")];
+        let errors = Config::load_from(None, env(&vars)).unwrap_err();
+        assert_eq!(
+            errors.0[0].to_string(),
+            "invalid claim_ttl_secs: must be longer than retry.max_elapsed_secs plus the wrangler timeouts"
+        );
+
+        let errors = Config::load_from(Some(Path::new("/nonexistent.toml")), env(R2_ENV));
+        assert!(matches!(
+            errors.unwrap_err().0.as_slice(),
+            [ConfigError::Read { .. }]
+        ));
+    }
+}
@@ -1,3 +0,0 @@
-pub static SENTRY_DSN: &str = "";
-
-pub static SENTRY_CRON_CHECK_URL: &str = "";
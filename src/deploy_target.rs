@@ -0,0 +1,635 @@
+use crate::{
+    asset_gc,
+    config::{Config, R2Config, S3StaticConfig},
+    errors::ProcessFileError,
+    pages::{self, DeploymentResult},
+    put_directory::put_directory,
+    retry::is_retryable_sdk,
+    types::{DeployMeta, TargetKind, UploadSummary},
+};
+use aws_config::BehaviorVersion;
+use aws_credential_types::{provider::SharedCredentialsProvider, Credentials};
+use aws_sdk_s3::{
+    config::http::HttpResponse,
+    error::SdkError,
+    types::{ErrorDocument, IndexDocument, WebsiteConfiguration},
+    Client,
+};
+use aws_types::region::Region;
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use tracing::{error, info, instrument, warn};
+
+/// Where a built site is published
+///
+/// [`publish`] calls [`prepare`](Self::prepare), [`upload`](Self::upload) then
+/// [`activate`](Self::activate), and [`rollback`](Self::rollback) when the upload or the
+/// activation fails.
+pub(crate) trait DeployTarget {
+    fn name(&self) -> &'static str;
+
+    /// Check the target is usable before anything is uploaded
+    async fn prepare(&self, site: &Site<'_>) -> Result<(), ProcessFileError>;
+
+    /// Upload the files of the release, visitors may not see them yet
+    async fn upload(&self, site: &Site<'_>) -> Result<UploadSummary, ProcessFileError>;
+
+    /// Make the uploaded release the live one, returns the deployment when the target creates one
+    async fn activate(&self, site: &Site<'_>)
+        -> Result<Option<DeploymentResult>, ProcessFileError>;
+
+    /// Undo what the upload left behind, best effort as the deploy already failed
+    async fn rollback(&self, site: &Site<'_>);
+}
+
+/// Extracted site of a release
+#[derive(Debug)]
+pub struct Site<'a> {
+    pub meta: &'a DeployMeta,
+    pub site_root: &'a Path,
+    /// Directory to publish, relative to `site_root` or absolute
+    pub deploy_path: &'a Path,
+}
+
+impl<'a> Site<'a> {
+    pub fn new(meta: &'a DeployMeta, site_root: &'a Path, deploy_path: &'a Path) -> Self {
+        Self {
+            meta,
+            site_root,
+            deploy_path,
+        }
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.site_root.join(self.deploy_path)
+    }
+}
+
+/// Publish the site to the target selected by its meta
+pub async fn publish(
+    config: &Config,
+    site: &Site<'_>,
+) -> Result<(UploadSummary, Option<DeploymentResult>), ProcessFileError> {
+    match site.meta.target {
+        TargetKind::Cloudflare => run(&Cloudflare::new(config, site.meta), site).await,
+        TargetKind::S3Static => run(&S3Static::new(config, site.meta), site).await,
+        TargetKind::LocalDir => run(&LocalDir::new(config), site).await,
+    }
+}
+
+#[instrument(err, skip_all, fields(target = target.name()))]
+async fn run(
+    target: &impl DeployTarget,
+    site: &Site<'_>,
+) -> Result<(UploadSummary, Option<DeploymentResult>), ProcessFileError> {
+    target.prepare(site).await?;
+
+    let res = async {
+        let summary = target.upload(site).await?;
+        let deployment = target.activate(site).await?;
+        Ok((summary, deployment))
+    }
+    .await;
+
+    if let Err(err) = &res {
+        warn!(?err, "deploy fail, roll back");
+        target.rollback(site).await;
+    }
+    res
+}
+
+/// Cloudflare Pages, the `_nuxt` assets of Nuxt sites go to R2
+#[derive(Debug)]
+pub struct Cloudflare<'a> {
+    config: &'a Config,
+    /// Only sites with a `_nuxt` directory use R2
+    r2_client: Option<Client>,
+    /// The manifest of the release was written before this attempt, by a release which may be live
+    manifest_existed: AtomicBool,
+}
+
+impl<'a> Cloudflare<'a> {
+    pub fn new(config: &'a Config, meta: &DeployMeta) -> Self {
+        Self {
+            config,
+            r2_client: (!meta.is_static()).then(|| create_r2_client(&config.r2)),
+            manifest_existed: AtomicBool::new(false),
+        }
+    }
+}
+
+impl DeployTarget for Cloudflare<'_> {
+    fn name(&self) -> &'static str {
+        "cloudflare"
+    }
+
+    async fn prepare(&self, site: &Site<'_>) -> Result<(), ProcessFileError> {
+        if let Some(r2_client) = &self.r2_client {
+            if !self.config.r2.is_set() {
+                return Err(ProcessFileError::TargetUnavailable {
+                    target: self.name(),
+                    reason: "r2 is not set, the _nuxt assets are uploaded to it",
+                });
+            }
+
+            let existed = asset_gc::manifest_exists(
+                r2_client,
+                self.config,
+                &site.meta.client_id,
+                &site.meta.release_id,
+            )
+            .await
+            .unwrap_or_else(|err| {
+                // keep the manifest on rollback when unsure
+                warn!(?err, "fail to check asset manifest");
+                true
+            });
+            self.manifest_existed.store(existed, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    async fn upload(&self, site: &Site<'_>) -> Result<UploadSummary, ProcessFileError> {
+        let Some(r2_client) = &self.r2_client else {
+            info!("skip put to r2");
+            return Ok(UploadSummary::default());
+        };
+
+        info!("put to r2");
+        let client_id = &site.meta.client_id;
+        let report = put_directory(
+            r2_client,
+            self.config,
+            &self.config.r2.bucket,
+            &format!("{client_id}/_nuxt"),
+            site.path().join("_nuxt"),
+        )
+        .await?;
+        info!(upload = ?report.summary, failed = ?report.failed, "r2 put success");
+
+        let mut summary = report.summary;
+        match asset_gc::write_manifest(
+            r2_client,
+            self.config,
+            client_id,
+            &site.meta.release_id,
+            report.keys,
+        )
+        .await
+        {
+            Ok(()) => summary.manifest_written = true,
+            Err(err) => {
+                error!(?err, "Fail to write asset manifest");
+                sentry::capture_error(&err);
+            }
+        }
+        Ok(summary)
+    }
+
+    async fn activate(
+        &self,
+        site: &Site<'_>,
+    ) -> Result<Option<DeploymentResult>, ProcessFileError> {
+        let deployment =
+            pages::deploy(self.config, site.meta, site.site_root, site.deploy_path).await?;
+        Ok(Some(deployment))
+    }
+
+    async fn rollback(&self, site: &Site<'_>) {
+        if self.manifest_existed.load(Ordering::Relaxed) {
+            info!("keep the asset manifest written before this attempt");
+            return;
+        }
+        // the assets are shared by releases, dropping the manifest lets gc decide
+        if let Some(r2_client) = &self.r2_client {
+            if let Err(err) = asset_gc::delete_manifest(
+                r2_client,
+                self.config,
+                &site.meta.client_id,
+                &site.meta.release_id,
+            )
+            .await
+            {
+                warn!(?err, "fail to delete asset manifest");
+            }
+        }
+    }
+}
+
+/// S3 compatible bucket served as a static website, one bucket per site
+#[derive(Debug)]
+pub struct S3Static<'a> {
+    config: &'a S3StaticConfig,
+    deployer_config: &'a Config,
+    client: Client,
+    bucket: String,
+}
+
+impl<'a> S3Static<'a> {
+    pub fn new(config: &'a Config, meta: &DeployMeta) -> Self {
+        let s3_static = &config.s3_static;
+        let endpoint = (!s3_static.endpoint_url.is_empty()).then(|| s3_static.endpoint_url.clone());
+        Self {
+            config: s3_static,
+            deployer_config: config,
+            client: create_s3_client(
+                endpoint,
+                &s3_static.region,
+                s3_static.force_path_style,
+                Credentials::new(
+                    &s3_static.access_key,
+                    &s3_static.secret_key,
+                    None,
+                    None,
+                    "s3_static",
+                ),
+            ),
+            bucket: s3_static
+                .bucket
+                .replace("{client_id}", &meta.client_id.to_lowercase()),
+        }
+    }
+
+    /// Invalid requests, which fail the same on every attempt
+    fn error(&self, source: impl std::error::Error + Send + Sync + 'static) -> ProcessFileError {
+        ProcessFileError::Target {
+            target: self.name(),
+            retryable: false,
+            source: Box::new(source),
+        }
+    }
+
+    fn aws_error<E>(&self, err: SdkError<E, HttpResponse>) -> ProcessFileError
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        ProcessFileError::Target {
+            target: self.name(),
+            retryable: is_retryable_sdk(&err),
+            source: Box::new(err),
+        }
+    }
+}
+
+impl DeployTarget for S3Static<'_> {
+    fn name(&self) -> &'static str {
+        "s3_static"
+    }
+
+    async fn prepare(&self, _site: &Site<'_>) -> Result<(), ProcessFileError> {
+        if self.config.bucket.is_empty() {
+            return Err(ProcessFileError::TargetUnavailable {
+                target: self.name(),
+                reason: "s3_static.bucket is not set",
+            });
+        }
+
+        self.client
+            .head_bucket()
+            .bucket(&self.bucket)
+            .send()
+            .await
+            .map_err(|err| self.aws_error(err))?;
+        Ok(())
+    }
+
+    async fn upload(&self, site: &Site<'_>) -> Result<UploadSummary, ProcessFileError> {
+        info!(bucket = self.bucket, "put site to s3");
+        let report = put_directory(
+            &self.client,
+            self.deployer_config,
+            &self.bucket,
+            "",
+            site.path(),
+        )
+        .await?;
+        info!(upload = ?report.summary, failed = ?report.failed, "s3 put success");
+        Ok(report.summary)
+    }
+
+    async fn activate(
+        &self,
+        site: &Site<'_>,
+    ) -> Result<Option<DeploymentResult>, ProcessFileError> {
+        let index_document = IndexDocument::builder()
+            .suffix(&self.config.index_document)
+            .build()
+            .map_err(|err| self.error(err))?;
+        // a website with a missing error document answers 403 to every missing key
+        let error_document = if site.path().join(&self.config.error_document).is_file() {
+            let document = ErrorDocument::builder()
+                .key(&self.config.error_document)
+                .build()
+                .map_err(|err| self.error(err))?;
+            Some(document)
+        } else {
+            None
+        };
+        let website = WebsiteConfiguration::builder()
+            .index_document(index_document)
+            .set_error_document(error_document)
+            .build();
+
+        self.client
+            .put_bucket_website()
+            .bucket(&self.bucket)
+            .website_configuration(website)
+            .send()
+            .await
+            .map_err(|err| self.aws_error(err))?;
+        Ok(None)
+    }
+
+    async fn rollback(&self, _site: &Site<'_>) {
+        // objects are replaced in place, the previous release is gone once uploaded
+        warn!(
+            bucket = self.bucket,
+            "site may be partially updated, redeploy the previous release to restore it"
+        );
+    }
+}
+
+/// Directory on the local file system, releases are kept side by side and
+/// `{root}/{client_id}/current` links to the live one
+#[derive(Debug)]
+pub struct LocalDir<'a> {
+    root: &'a Path,
+}
+
+impl<'a> LocalDir<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        Self {
+            root: &config.local_dir.root,
+        }
+    }
+
+    fn site_dir(&self, site: &Site<'_>) -> PathBuf {
+        self.root.join(&site.meta.client_id)
+    }
+
+    fn release_dir(&self, site: &Site<'_>) -> PathBuf {
+        self.site_dir(site)
+            .join("releases")
+            .join(&site.meta.release_id)
+    }
+
+    /// The release dir, checked to be a plain sub dir of `root` before it is removed
+    fn removable_release_dir(&self, site: &Site<'_>) -> io::Result<PathBuf> {
+        let release_dir = self.release_dir(site);
+        let inside = release_dir
+            .strip_prefix(self.root)
+            .is_ok_and(|path| path.components().all(|c| matches!(c, Component::Normal(_))));
+        if !inside {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is outside of the root", release_dir.display()),
+            ));
+        }
+        Ok(release_dir)
+    }
+}
+
+impl DeployTarget for LocalDir<'_> {
+    fn name(&self) -> &'static str {
+        "local_dir"
+    }
+
+    async fn prepare(&self, site: &Site<'_>) -> Result<(), ProcessFileError> {
+        if self.root.as_os_str().is_empty() {
+            return Err(ProcessFileError::TargetUnavailable {
+                target: self.name(),
+                reason: "local_dir.root is not set",
+            });
+        }
+
+        tokio::fs::create_dir_all(self.site_dir(site).join("releases")).await?;
+        Ok(())
+    }
+
+    async fn upload(&self, site: &Site<'_>) -> Result<UploadSummary, ProcessFileError> {
+        let from = site.path();
+        let to = self.removable_release_dir(site)?;
+        info!(to = %to.display(), "copy site");
+
+        let copied = tokio::task::spawn_blocking(move || {
+            // a redelivered release starts over
+            if to.exists() {
+                fs::remove_dir_all(&to)?;
+            }
+            copy_dir(&from, &to)
+        })
+        .await
+        .map_err(ProcessFileError::JoinError)??;
+
+        Ok(UploadSummary {
+            uploaded: copied,
+            ..Default::default()
+        })
+    }
+
+    async fn activate(
+        &self,
+        site: &Site<'_>,
+    ) -> Result<Option<DeploymentResult>, ProcessFileError> {
+        let site_dir = self.site_dir(site);
+        let link = site_dir.join(".current.tmp");
+        if tokio::fs::symlink_metadata(&link).await.is_ok() {
+            tokio::fs::remove_file(&link).await?;
+        }
+
+        // renaming over the old link swaps the release atomically
+        tokio::fs::symlink(self.release_dir(site), &link).await?;
+        tokio::fs::rename(&link, site_dir.join("current")).await?;
+        Ok(None)
+    }
+
+    async fn rollback(&self, site: &Site<'_>) {
+        let release_dir = match self.removable_release_dir(site) {
+            Ok(release_dir) => release_dir,
+            Err(err) => {
+                warn!(?err, "skip removing the release");
+                return;
+            }
+        };
+        let current = tokio::fs::read_link(self.site_dir(site).join("current")).await;
+        if current.is_ok_and(|current| current == release_dir) {
+            return;
+        }
+
+        if let Err(err) = tokio::fs::remove_dir_all(&release_dir).await {
+            if err.kind() != io::ErrorKind::NotFound {
+                warn!(?err, "fail to remove {}", release_dir.display());
+            }
+        }
+    }
+}
+
+/// Copy the files under `from`, following the links which stay inside it, returns the number of
+/// files copied
+fn copy_dir(from: &Path, to: &Path) -> io::Result<i32> {
+    let real_root = fs::canonicalize(from)?;
+    let mut visited = HashSet::from([real_root.clone()]);
+    copy_entries(from, to, &real_root, &mut visited)
+}
+
+/// Each real dir is copied once, so a link to a parent dir doesn't loop
+fn copy_entries(
+    from: &Path,
+    to: &Path,
+    real_root: &Path,
+    visited: &mut HashSet<PathBuf>,
+) -> io::Result<i32> {
+    fs::create_dir_all(to)?;
+    let mut copied = 0;
+    for entry in fs::read_dir(from)? {
+        let path = entry?.path();
+        let target = to.join(path.file_name().unwrap_or_default());
+        let real_path = fs::canonicalize(&path)?;
+        if !real_path.starts_with(real_root) {
+            warn!(path = %path.display(), "skip link outside of the site");
+            continue;
+        }
+        if real_path.is_dir() {
+            if visited.insert(real_path) {
+                copied += copy_entries(&path, &target, real_root, visited)?;
+            }
+        } else {
+            fs::copy(&path, &target)?;
+            copied += 1;
+        }
+    }
+    Ok(copied)
+}
+
+pub(crate) fn create_r2_client(config: &R2Config) -> Client {
+    let credentials = Credentials::new(&config.access_key, &config.secret_key, None, None, "r2");
+    // validated at startup once R2 is set, Cloudflare::prepare fails before it is used otherwise
+    create_s3_client(
+        config.endpoint(),
+        &config.region,
+        config.force_path_style,
+        credentials,
+    )
+}
+
+fn create_s3_client(
+    endpoint: Option<String>,
+    region: &str,
+    force_path_style: bool,
+    credentials: Credentials,
+) -> Client {
+    let mut builder = aws_sdk_s3::Config::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .credentials_provider(SharedCredentialsProvider::new(credentials))
+        .region(Region::new(region.to_owned()))
+        .force_path_style(force_path_style)
+        // uploads are retried under `config.retry`, SDK retries would multiply the attempts
+        .retry_config(aws_config::retry::RetryConfig::disabled());
+    builder.set_endpoint_url(endpoint);
+    Client::from_conf(builder.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_smithy_types::byte_stream::ByteStream;
+
+    #[tokio::test]
+    async fn test_local_dir_target() {
+        let root = tempfile::tempdir().unwrap();
+        let build = tempfile::tempdir().unwrap();
+        fs::create_dir_all(build.path().join("dist/_nuxt")).unwrap();
+        fs::write(build.path().join("dist/index.html"), "v1").unwrap();
+        fs::write(build.path().join("dist/_nuxt/entry.js"), "1").unwrap();
+        // points back to the site, which is already being copied
+        std::os::unix::fs::symlink("..", build.path().join("dist/_nuxt/loop")).unwrap();
+
+        let mut config = Config::default();
+        config.local_dir.root = root.path().to_owned();
+        let current = root.path().join("P1/current");
+
+        let meta = DeployMeta {
+            client_id: "P1".to_owned(),
+            release_id: "1".to_owned(),
+            target: TargetKind::LocalDir,
+            ..Default::default()
+        };
+        let site = Site::new(&meta, build.path(), Path::new("dist"));
+        let (summary, deployment) = publish(&config, &site).await.unwrap();
+        assert_eq!(summary.uploaded, 2);
+        assert_eq!(deployment, None, "no deployment outside Pages");
+        assert_eq!(
+            fs::read_to_string(current.join("index.html")).unwrap(),
+            "v1"
+        );
+
+        fs::write(build.path().join("dist/index.html"), "v2").unwrap();
+        let meta = DeployMeta {
+            release_id: "2".to_owned(),
+            ..meta
+        };
+        let site = Site::new(&meta, build.path(), Path::new("dist"));
+        publish(&config, &site).await.unwrap();
+        assert_eq!(
+            fs::read_to_string(current.join("index.html")).unwrap(),
+            "v2"
+        );
+        assert_eq!(
+            fs::read_link(&current).unwrap(),
+            root.path().join("P1/releases/2")
+        );
+
+        // the live release is kept, the others are removed
+        let target = LocalDir::new(&config);
+        target.rollback(&site).await;
+        assert!(root.path().join("P1/releases/2").is_dir());
+        let meta = DeployMeta {
+            release_id: "1".to_owned(),
+            ..meta
+        };
+        let site = Site::new(&meta, build.path(), Path::new("dist"));
+        target.rollback(&site).await;
+        assert!(!root.path().join("P1/releases/1").exists());
+
+        // ids are validated when parsed, the dir is checked again before anything is removed
+        let meta = DeployMeta {
+            release_id: "../..".to_owned(),
+            ..meta
+        };
+        let site = Site::new(&meta, build.path(), Path::new("dist"));
+        target.rollback(&site).await;
+        assert!(root.path().join("P1/releases/2").is_dir());
+
+        config.local_dir.root = PathBuf::new();
+        assert!(matches!(
+            publish(&config, &site).await,
+            Err(ProcessFileError::TargetUnavailable { .. })
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore] // default disable as it needs a local S3, e.g. `localstack` with a `storipress` bucket
+    async fn test_r2_client_path_style() {
+        let client = create_r2_client(&R2Config {
+            endpoint_url: "http://localhost:4566".to_owned(),
+            region: "us-east-1".to_owned(),
+            force_path_style: true,
+            access_key: "test".to_owned(),
+            secret_key: "test".to_owned(),
+            ..Default::default()
+        });
+
+        client
+            .put_object()
+            .bucket("storipress")
+            .key("_nuxt/path-style.js")
+            .body(ByteStream::from_static(b"ok"))
+            .send()
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,692 @@
+use crate::{
+    config::Config,
+    errors::ProcessFileError,
+    http::CLIENT,
+    pages::{DeploymentResult, PagesDeployer},
+    retry::{is_retryable_io, Retryable},
+    types::DeployMeta,
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use reqwest::{header::CONTENT_TYPE, StatusCode};
+use reqwest_middleware::RequestBuilder;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::time::timeout;
+use tracing::{debug, info, instrument, warn};
+
+/// Limits of the Pages Direct Upload API, the same as wrangler
+const MAX_ASSET_SIZE: u64 = 25 * 1024 * 1024;
+const MAX_ASSET_COUNT: usize = 20_000;
+const MAX_BUCKET_SIZE: u64 = 40 * 1024 * 1024;
+const MAX_BUCKET_FILE_COUNT: usize = 2000;
+
+/// Files in the root which configure the deployment instead of being served
+const CONFIG_FILES: &[&str] = &["_worker.js", "_routes.json", "_headers", "_redirects"];
+/// Never uploaded, wherever they are
+const IGNORED_FILES: &[&str] = &[".DS_Store", "node_modules", ".git"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Http(#[from] reqwest_middleware::Error),
+    #[error(transparent)]
+    Decode(#[from] reqwest::Error),
+    #[error("Pages API responds {status}: {message}")]
+    Api { status: StatusCode, message: String },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("asset {} is larger than 25 MiB", .0.display())]
+    AssetTooLarge(PathBuf),
+    #[error("more than {MAX_ASSET_COUNT} assets")]
+    TooManyAssets,
+    #[error("no Cloudflare account for the deployment")]
+    MissingAccount,
+    #[error("no deployment after {0:?}")]
+    Timeout(Duration),
+}
+
+impl Retryable for Error {
+    fn is_retryable(&self) -> bool {
+        match self {
+            // the HTTP client already retries transient failures, what is left is the connection
+            Error::Http(_) | Error::Decode(_) => true,
+            Error::Api { status, .. } => {
+                status.is_server_error()
+                    || matches!(
+                        *status,
+                        StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS
+                    )
+            }
+            Error::Io(err) => is_retryable_io(err),
+            Error::Timeout(_) => true,
+            Error::AssetTooLarge(_) | Error::TooManyAssets | Error::MissingAccount => false,
+        }
+    }
+}
+
+/// Response envelope of the Cloudflare API
+#[derive(Debug, Deserialize)]
+struct Envelope {
+    success: bool,
+    #[serde(default)]
+    errors: Vec<ApiMessage>,
+    /// `null` on some endpoints, e.g. upload
+    #[serde(default)]
+    result: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiMessage {
+    code: i64,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadToken {
+    jwt: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Deployment {
+    id: String,
+    url: String,
+}
+
+#[derive(Debug, Serialize)]
+struct UploadPayload {
+    key: String,
+    value: String,
+    metadata: UploadMetadata,
+    base64: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadMetadata {
+    content_type: String,
+}
+
+/// File served by Pages
+#[derive(Debug)]
+struct Asset {
+    path: PathBuf,
+    /// Path from the deploy root with a leading `/`
+    name: String,
+    hash: String,
+    size: u64,
+    content_type: String,
+}
+
+/// Client of the Pages Direct Upload flow used by `wrangler pages deploy`
+#[derive(Debug)]
+pub struct DirectUpload<'a> {
+    config: &'a Config,
+}
+
+impl PagesDeployer for DirectUpload<'_> {
+    #[instrument(err, skip(self, meta))]
+    async fn deploy(
+        &self,
+        meta: &DeployMeta,
+        site_root: &Path,
+        deploy_path: &Path,
+    ) -> Result<DeploymentResult, ProcessFileError> {
+        let account_id = meta
+            .environment
+            .as_ref()
+            .and_then(|environment| environment.cloudflare_account_id.as_deref())
+            .unwrap_or(&self.config.pages.account_id);
+        if account_id.is_empty() {
+            return Err(Error::MissingAccount.into());
+        }
+
+        let project = &meta.page_id;
+        let branch = &meta.client_id;
+        let root = site_root.join(deploy_path);
+        // same budget as wrangler
+        let limit = Duration::from_secs(if meta.is_static() {
+            self.config.wrangler.static_timeout_secs
+        } else {
+            self.config.wrangler.timeout_secs
+        });
+
+        timeout(
+            limit,
+            self.deploy_project(account_id, project, branch, &root),
+        )
+        .await
+        .map_err(|_| Error::Timeout(limit))?
+        .map_err(process_error)
+    }
+}
+
+/// The API errors wrangler reports are classified the same, whichever backend deploys
+fn process_error(err: Error) -> ProcessFileError {
+    match err {
+        Error::Api { status, .. } => match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ProcessFileError::WranglerAuth,
+            StatusCode::NOT_FOUND => ProcessFileError::PagesProjectNotFound,
+            StatusCode::TOO_MANY_REQUESTS => ProcessFileError::PagesRateLimited,
+            _ => err.into(),
+        },
+        err => err.into(),
+    }
+}
+
+impl<'a> DirectUpload<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        Self { config }
+    }
+
+    /// Reason why the site needs wrangler, which bundles Pages Functions and worker directories
+    pub fn unsupported(root: &Path) -> Option<&'static str> {
+        if root.join("functions").is_dir() {
+            Some("Pages Functions need to be bundled")
+        } else if root.join("_worker.js").is_dir() {
+            Some("_worker.js directory needs to be bundled")
+        } else {
+            None
+        }
+    }
+
+    pub async fn deploy_project(
+        &self,
+        account_id: &str,
+        project: &str,
+        branch: &str,
+        root: &Path,
+    ) -> Result<DeploymentResult, Error> {
+        let assets = {
+            let root = root.to_owned();
+            tokio::task::spawn_blocking(move || collect_assets(&root))
+                .await
+                .map_err(io::Error::other)??
+        };
+        info!(assets = assets.len(), "collect assets");
+
+        let mut jwt = self.upload_token(account_id, project).await?;
+        let hashes = assets
+            .iter()
+            .map(|asset| asset.hash.clone())
+            .collect::<Vec<_>>();
+        let missing = self.check_missing(&jwt, &hashes).await?;
+        let missing = assets
+            .iter()
+            .filter(|asset| missing.contains(&asset.hash))
+            .collect::<Vec<_>>();
+        info!(missing = missing.len(), "upload missing assets");
+
+        for bucket in buckets(&missing) {
+            match self.upload(&jwt, bucket).await {
+                // the token is short lived, large sites outlive it
+                Err(Error::Api {
+                    status: StatusCode::UNAUTHORIZED,
+                    ..
+                }) => {
+                    debug!("upload token expired, renew it");
+                    jwt = self.upload_token(account_id, project).await?;
+                    self.upload(&jwt, bucket).await?;
+                }
+                res => res?,
+            }
+        }
+        self.upsert_hashes(&jwt, &hashes).await?;
+
+        let manifest = assets
+            .iter()
+            .map(|asset| (asset.name.as_str(), asset.hash.as_str()))
+            .collect::<BTreeMap<_, _>>();
+        let deployment = self
+            .create_deployment(account_id, project, branch, root, &manifest)
+            .await?;
+
+        Ok(DeploymentResult {
+            deployment_id: Some(deployment.id),
+            url: Some(deployment.url),
+            uploaded: Some(missing.len() as u32),
+            skipped: Some((assets.len() - missing.len()) as u32),
+        })
+    }
+
+    async fn upload_token(&self, account_id: &str, project: &str) -> Result<String, Error> {
+        let url = self.url(&format!(
+            "/accounts/{account_id}/pages/projects/{project}/upload-token"
+        ));
+        let token: UploadToken = send(CLIENT.get(url).bearer_auth(self.api_token())).await?;
+        Ok(token.jwt)
+    }
+
+    async fn check_missing(&self, jwt: &str, hashes: &[String]) -> Result<Vec<String>, Error> {
+        let url = self.url("/pages/assets/check-missing");
+        let body = serde_json::json!({ "hashes": hashes });
+        send(CLIENT.post(url).bearer_auth(jwt).json(&body)).await
+    }
+
+    async fn upload(&self, jwt: &str, bucket: &[&Asset]) -> Result<(), Error> {
+        let mut payload = Vec::with_capacity(bucket.len());
+        for asset in bucket {
+            payload.push(UploadPayload {
+                key: asset.hash.clone(),
+                value: STANDARD.encode(tokio::fs::read(&asset.path).await?),
+                metadata: UploadMetadata {
+                    content_type: asset.content_type.clone(),
+                },
+                base64: true,
+            });
+        }
+
+        debug!(files = payload.len(), "upload bucket");
+        let url = self.url("/pages/assets/upload");
+        send(CLIENT.post(url).bearer_auth(jwt).json(&payload)).await
+    }
+
+    async fn upsert_hashes(&self, jwt: &str, hashes: &[String]) -> Result<(), Error> {
+        let url = self.url("/pages/assets/upsert-hashes");
+        let body = serde_json::json!({ "hashes": hashes });
+        send(CLIENT.post(url).bearer_auth(jwt).json(&body)).await
+    }
+
+    async fn create_deployment(
+        &self,
+        account_id: &str,
+        project: &str,
+        branch: &str,
+        root: &Path,
+        manifest: &BTreeMap<&str, &str>,
+    ) -> Result<Deployment, Error> {
+        let manifest = serde_json::to_string(manifest).expect("manifest is always serializable");
+        let mut form = MultipartForm::default();
+        form.field("manifest", None, manifest.as_bytes());
+        form.field("branch", None, branch.as_bytes());
+        for name in CONFIG_FILES {
+            let path = root.join(name);
+            if path.is_file() {
+                debug!(name, "attach config file");
+                let content = tokio::fs::read(&path).await?;
+                form.field(name, Some(name), &content);
+            }
+        }
+
+        let url = self.url(&format!(
+            "/accounts/{account_id}/pages/projects/{project}/deployments"
+        ));
+        send(
+            CLIENT
+                .post(url)
+                .bearer_auth(self.api_token())
+                .header(CONTENT_TYPE, form.content_type())
+                .body(form.finish()),
+        )
+        .await
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.config.pages.api_base.trim_end_matches('/'))
+    }
+
+    fn api_token(&self) -> &str {
+        &self.config.pages.api_token
+    }
+}
+
+async fn send<T: DeserializeOwned>(request: RequestBuilder) -> Result<T, Error> {
+    let res = request.send().await?;
+    let status = res.status();
+    let body = res.text().await?;
+
+    let envelope = serde_json::from_str::<Envelope>(&body).and_then(|envelope| {
+        if envelope.success {
+            serde_json::from_value(envelope.result).map(Ok)
+        } else {
+            Ok(Err(envelope.errors))
+        }
+    });
+    match envelope {
+        Ok(Ok(result)) => Ok(result),
+        Ok(Err(errors)) => {
+            let message = errors
+                .iter()
+                .map(|err| format!("{} ({})", err.message, err.code))
+                .collect::<Vec<_>>()
+                .join(", ");
+            Err(Error::Api { status, message })
+        }
+        Err(err) => {
+            warn!(?err, body, "unexpected response");
+            Err(Error::Api {
+                status,
+                message: err.to_string(),
+            })
+        }
+    }
+}
+
+/// `multipart/form-data` built in memory, unlike `reqwest::multipart::Form` the request
+/// stays clonable so the retry middleware can resend it
+struct MultipartForm {
+    boundary: String,
+    body: Vec<u8>,
+}
+
+impl Default for MultipartForm {
+    fn default() -> Self {
+        Self {
+            boundary: format!("deployer-{}", uuid::Uuid::new_v4().simple()),
+            body: Vec::new(),
+        }
+    }
+}
+
+impl MultipartForm {
+    fn field(&mut self, name: &str, file_name: Option<&str>, content: &[u8]) {
+        let mut disposition = format!("form-data; name=\"{name}\"");
+        if let Some(file_name) = file_name {
+            disposition.push_str(&format!("; filename=\"{file_name}\""));
+        }
+        self.body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: {disposition}\r\n\r\n",
+                self.boundary
+            )
+            .as_bytes(),
+        );
+        self.body.extend_from_slice(content);
+        self.body.extend_from_slice(b"\r\n");
+    }
+
+    fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.body
+            .extend_from_slice(format!("--{}--\r\n", self.boundary).as_bytes());
+        self.body
+    }
+}
+
+fn collect_assets(root: &Path) -> Result<Vec<Asset>, Error> {
+    let real_root = fs::canonicalize(root)?;
+    let mut visited = HashSet::from([real_root.clone()]);
+    let mut assets = Vec::new();
+    collect_dir(root, root, &real_root, &mut visited, &mut assets)?;
+    Ok(assets)
+}
+
+/// Links are followed as long as they stay inside the site, each real dir is walked once
+fn collect_dir(
+    root: &Path,
+    dir: &Path,
+    real_root: &Path,
+    visited: &mut HashSet<PathBuf>,
+    assets: &mut Vec<Asset>,
+) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let file_name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        if IGNORED_FILES.contains(&file_name.as_str())
+            || (dir == root
+                && (CONFIG_FILES.contains(&file_name.as_str()) || file_name == "functions"))
+        {
+            continue;
+        }
+
+        let real_path = fs::canonicalize(&path)?;
+        if !real_path.starts_with(real_root) {
+            warn!(path = %path.display(), "skip link outside of the site");
+            continue;
+        }
+        let metadata = fs::metadata(&real_path)?;
+        if metadata.is_dir() {
+            if visited.insert(real_path) {
+                collect_dir(root, &path, real_root, visited, assets)?;
+            }
+            continue;
+        }
+        if metadata.len() > MAX_ASSET_SIZE {
+            return Err(Error::AssetTooLarge(path));
+        }
+        if assets.len() >= MAX_ASSET_COUNT {
+            return Err(Error::TooManyAssets);
+        }
+
+        let relative_path = path.strip_prefix(root).unwrap_or(&path);
+        let content = fs::read(&path)?;
+        assets.push(Asset {
+            name: format!("/{}", relative_path.to_string_lossy()),
+            hash: hash_file(&path, &content),
+            size: metadata.len(),
+            content_type: mime_guess::from_path(&path)
+                .first_or_octet_stream()
+                .essence_str()
+                .to_owned(),
+            path,
+        });
+    }
+    Ok(())
+}
+
+/// Same hash as wrangler, so assets uploaded by either backend are shared
+fn hash_file(path: &Path, content: &[u8]) -> String {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy())
+        .unwrap_or_default();
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(STANDARD.encode(content).as_bytes());
+    hasher.update(extension.as_bytes());
+    hasher.finalize().to_hex()[..32].to_owned()
+}
+
+/// Group assets into upload requests within the size and count limits
+fn buckets<'a, 'b>(assets: &'b [&'a Asset]) -> Vec<&'b [&'a Asset]> {
+    let mut buckets = Vec::new();
+    let mut start = 0;
+    let mut size = 0;
+    for (index, asset) in assets.iter().enumerate() {
+        if index > start
+            && (size + asset.size > MAX_BUCKET_SIZE || index - start >= MAX_BUCKET_FILE_COUNT)
+        {
+            buckets.push(&assets[start..index]);
+            start = index;
+            size = 0;
+        }
+        size += asset.size;
+    }
+    if start < assets.len() {
+        buckets.push(&assets[start..]);
+    }
+    buckets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::{
+        matchers::{body_string_contains, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn ok(result: Value) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "success": true,
+            "errors": [],
+            "messages": [],
+            "result": result,
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_deploy_project() {
+        let site = tempfile::tempdir().unwrap();
+        let root = site.path();
+        fs::create_dir_all(root.join("_nuxt")).unwrap();
+        fs::write(root.join("index.html"), "<html></html>").unwrap();
+        fs::write(root.join("_nuxt/entry.js"), "console.log(1)").unwrap();
+        fs::write(root.join("_worker.js"), "export default {}").unwrap();
+        fs::write(root.join("_routes.json"), r#"{"version":1}"#).unwrap();
+        fs::write(root.join(".DS_Store"), "").unwrap();
+
+        let entry_hash = hash_file(Path::new("entry.js"), b"console.log(1)");
+        let server = MockServer::start().await;
+        let project = "/accounts/account/pages/projects/P1";
+
+        Mock::given(method("GET"))
+            .and(path(format!("{project}/upload-token")))
+            .and(header("authorization", "Bearer api-token"))
+            .respond_with(ok(serde_json::json!({ "jwt": "jwt" })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/pages/assets/check-missing"))
+            .and(header("authorization", "Bearer jwt"))
+            .respond_with(ok(serde_json::json!([entry_hash])))
+            .expect(1)
+            .mount(&server)
+            .await;
+        // only the missing asset is uploaded
+        Mock::given(method("POST"))
+            .and(path("/pages/assets/upload"))
+            .and(body_string_contains(entry_hash.as_str()))
+            .respond_with(ok(Value::Null))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/pages/assets/upsert-hashes"))
+            .respond_with(ok(Value::Null))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(format!("{project}/deployments")))
+            .and(body_string_contains("/_nuxt/entry.js"))
+            .and(body_string_contains("filename=\"_worker.js\""))
+            .and(body_string_contains("filename=\"_routes.json\""))
+            .respond_with(ok(serde_json::json!({
+                "id": "deployment-id",
+                "url": "https://abc.p1.pages.dev",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut config = Config::default();
+        config.pages.api_base = server.uri();
+        config.pages.api_token = "api-token".to_owned();
+        let client = DirectUpload::new(&config);
+
+        let deployment = client
+            .deploy_project("account", "P1", "P1", root)
+            .await
+            .unwrap();
+        assert_eq!(
+            deployment,
+            DeploymentResult {
+                deployment_id: Some("deployment-id".to_owned()),
+                url: Some("https://abc.p1.pages.dev".to_owned()),
+                uploaded: Some(1),
+                skipped: Some(1),
+            }
+        );
+
+        let assets = collect_assets(root).unwrap();
+        let mut names = assets
+            .iter()
+            .map(|asset| asset.name.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["/_nuxt/entry.js", "/index.html"]);
+    }
+
+    #[tokio::test]
+    async fn test_api_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(403).set_body_json(serde_json::json!({
+                "success": false,
+                "errors": [{ "code": 10000, "message": "Authentication error" }],
+                "result": null,
+            })))
+            .mount(&server)
+            .await;
+
+        let mut config = Config::default();
+        config.pages.api_base = server.uri();
+        let err = DirectUpload::new(&config)
+            .upload_token("account", "P1")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Pages API responds 403 Forbidden: Authentication error (10000)"
+        );
+        assert!(matches!(process_error(err), ProcessFileError::WranglerAuth));
+
+        let api = |status| Error::Api {
+            status,
+            message: String::new(),
+        };
+        assert!(matches!(
+            process_error(api(StatusCode::NOT_FOUND)),
+            ProcessFileError::PagesProjectNotFound
+        ));
+        assert!(matches!(
+            process_error(api(StatusCode::TOO_MANY_REQUESTS)),
+            ProcessFileError::PagesRateLimited
+        ));
+        assert!(matches!(
+            process_error(api(StatusCode::BAD_GATEWAY)),
+            ProcessFileError::DirectUpload(_)
+        ));
+    }
+
+    #[test]
+    fn test_collect_assets_links() {
+        let site = tempfile::tempdir().unwrap();
+        let root = site.path();
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::write(root.join("index.html"), "<html></html>").unwrap();
+        std::os::unix::fs::symlink("index.html", root.join("latest.html")).unwrap();
+        std::os::unix::fs::symlink("..", root.join("a/loop")).unwrap();
+        std::os::unix::fs::symlink("/etc", root.join("etc")).unwrap();
+
+        let mut names = collect_assets(root)
+            .unwrap()
+            .into_iter()
+            .map(|asset| asset.name)
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["/index.html", "/latest.html"]);
+    }
+
+    #[test]
+    fn test_buckets() {
+        let asset = |size| Asset {
+            path: PathBuf::new(),
+            name: String::new(),
+            hash: String::new(),
+            size,
+            content_type: String::new(),
+        };
+        let assets = [asset(30 << 20), asset(20 << 20), asset(1), asset(1)];
+        let assets = assets.iter().collect::<Vec<_>>();
+        let sizes = buckets(&assets)
+            .iter()
+            .map(|bucket| bucket.len())
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, [1, 3]);
+    }
+}
@@ -0,0 +1,111 @@
+use serde_derive::Deserialize;
+use std::str::FromStr;
+
+/// A Storipress deployment the releases may come from, picked by the prefix of the client id
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct Environment {
+    pub name: String,
+    pub client_prefix: String,
+    pub api_base: String,
+    /// Cloudflare account of the Pages projects, falls back to the account of the wrangler credentials
+    pub cloudflare_account_id: Option<String>,
+}
+
+impl Environment {
+    fn builtin(name: &str, client_prefix: &str, api_base: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            client_prefix: client_prefix.to_owned(),
+            api_base: api_base.to_owned(),
+            cloudflare_account_id: None,
+        }
+    }
+}
+
+/// Built-in environments plus the configured ones, given as a list of [`Environment`]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "Vec<Environment>")]
+pub struct EnvironmentRegistry {
+    environments: Vec<Environment>,
+}
+
+impl Default for EnvironmentRegistry {
+    fn default() -> Self {
+        Self {
+            environments: vec![
+                Environment::builtin("production", "P", "https://api.stori.press"),
+                Environment::builtin("staging", "S", "https://api.storipress.pro"),
+                Environment::builtin("development", "D", "https://api.storipress.dev"),
+            ],
+        }
+    }
+}
+
+impl From<Vec<Environment>> for EnvironmentRegistry {
+    fn from(environments: Vec<Environment>) -> Self {
+        let mut registry = Self::default();
+        for environment in environments {
+            registry.insert(environment);
+        }
+        registry
+    }
+}
+
+/// Parse a JSON array of [`Environment`]
+impl FromStr for EnvironmentRegistry {
+    type Err = serde_json::Error;
+
+    fn from_str(json: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str::<Vec<Environment>>(json).map(Self::from)
+    }
+}
+
+impl EnvironmentRegistry {
+    /// Add an environment, replacing the one with the same name
+    pub fn insert(&mut self, environment: Environment) {
+        self.environments
+            .retain(|existing| existing.name != environment.name);
+        self.environments.push(environment);
+    }
+
+    /// Find the environment of a client, the longest matching prefix wins
+    /// so e.g. `PL` can be a local environment besides `P`
+    pub fn resolve(&self, client_id: &str) -> Option<&Environment> {
+        self.environments
+            .iter()
+            .filter(|environment| client_id.starts_with(&environment.client_prefix))
+            .max_by_key(|environment| environment.client_prefix.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let mut registry = EnvironmentRegistry::default();
+        assert_eq!(registry.resolve("P123").unwrap().name, "production");
+        assert_eq!(registry.resolve("D123").unwrap().name, "development");
+        assert!(registry.resolve("X123").is_none());
+
+        let local = EnvironmentRegistry::from_str(
+            r#"[{"name":"local","client_prefix":"PL","api_base":"http://localhost:8080","cloudflare_account_id":"abc"}]"#,
+        )
+        .unwrap();
+        let environment = local.resolve("PL123").unwrap();
+        assert_eq!(environment.name, "local");
+        assert_eq!(environment.cloudflare_account_id.as_deref(), Some("abc"));
+        assert_eq!(local.resolve("P123").unwrap().name, "production");
+
+        registry.insert(Environment::builtin(
+            "staging",
+            "S",
+            "http://localhost:8081",
+        ));
+        assert_eq!(
+            registry.resolve("S123").unwrap().api_base,
+            "http://localhost:8081"
+        );
+    }
+}
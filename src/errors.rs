@@ -1,9 +1,13 @@
-use serde_json::Error;
+use crate::{
+    retry::{is_retryable_io, Retryable},
+    types::MetaError,
+};
 use std::{
     error::Error as StdError,
     fmt::{self, Debug, Display},
+    time::Duration,
 };
-use tokio::{task::JoinError, time::error::Elapsed};
+use tokio::task::JoinError;
 
 #[derive(Debug, thiserror::Error)]
 pub enum ProcessFileError {
@@ -13,17 +17,30 @@ pub enum ProcessFileError {
     EmptyMeta,
     #[error("Storipress meta not found")]
     NoMeta,
-    #[error("Invalid meta {meta}")]
+    #[error("Invalid meta {meta}: {}", .errors.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))]
     InvalidMeta {
         meta: String,
-        #[source]
-        source: Error,
+        errors: Vec<MetaError>,
     },
-    #[error("Deploy fail {:?}", 0)]
+    #[error("Invalid meta signature: {0}")]
+    InvalidSignature(#[from] crate::signature::SignatureError),
+    #[error("Deploy fail with exit code {0:?}")]
     DeployFail(Option<i32>),
 
-    #[error("wrangler timeout after {}", 0)]
-    WranglerTimeout(#[from] Elapsed),
+    #[error("wrangler is not authorized, check CLOUDFLARE_API_TOKEN")]
+    WranglerAuth,
+
+    #[error("Pages project not found")]
+    PagesProjectNotFound,
+
+    #[error("Pages rejects a file larger than 25 MiB: {0}")]
+    PagesFileTooLarge(String),
+
+    #[error("Rate limited by the Cloudflare API")]
+    PagesRateLimited,
+
+    #[error("wrangler timeout after {0:?}")]
+    WranglerTimeout(Duration),
 
     #[error(transparent)]
     AggregateError(#[from] AggregateError<Box<ProcessFileError>>),
@@ -38,8 +55,72 @@ pub enum ProcessFileError {
     #[error(transparent)]
     Io(#[from] std::io::Error),
 
+    #[error("Archive rejected: {0}")]
+    Extract(#[from] crate::extract::ExtractError),
+
+    #[error("Archive integrity check fail: {0}")]
+    Integrity(#[from] crate::integrity::IntegrityError),
+
     #[error("Join error")]
     JoinError(#[source] JoinError),
+
+    #[error("Cancelled due to shutdown")]
+    Cancelled,
+
+    #[error("Release is being deployed by another worker")]
+    Claimed,
+
+    #[error("Pages direct upload fail: {0}")]
+    DirectUpload(#[from] crate::direct_upload::Error),
+
+    #[error("Deploy target {target} unavailable: {reason}")]
+    TargetUnavailable {
+        target: &'static str,
+        reason: &'static str,
+    },
+
+    #[error("Deploy target {target} fail: {source}")]
+    Target {
+        target: &'static str,
+        /// Classified when the error is created, the source is type erased
+        retryable: bool,
+        #[source]
+        source: Box<dyn StdError + Send + Sync>,
+    },
+}
+
+impl Retryable for ProcessFileError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            ProcessFileError::S3Error
+            | ProcessFileError::DeployFail(_)
+            | ProcessFileError::WranglerTimeout(_)
+            | ProcessFileError::PagesRateLimited => true,
+            ProcessFileError::Target { retryable, .. } => *retryable,
+            // the last attempt tells the most recent state
+            ProcessFileError::AggregateError(errors) => {
+                errors.iter().last().is_some_and(|err| err.is_retryable())
+            }
+            ProcessFileError::R2Error(err) => err.is_retryable(),
+            ProcessFileError::DirectUpload(err) => err.is_retryable(),
+            ProcessFileError::Io(err) => is_retryable_io(err),
+            ProcessFileError::EmptyMeta
+            | ProcessFileError::NoMeta
+            | ProcessFileError::InvalidMeta { .. }
+            | ProcessFileError::InvalidSignature(_)
+            | ProcessFileError::WranglerAuth
+            | ProcessFileError::PagesProjectNotFound
+            | ProcessFileError::PagesFileTooLarge(_)
+            | ProcessFileError::Extract(_)
+            | ProcessFileError::Integrity(_)
+            | ProcessFileError::JoinError(_)
+            | ProcessFileError::Cancelled
+            | ProcessFileError::Claimed
+            | ProcessFileError::TargetUnavailable { .. } => false,
+            #[cfg(feature = "intended_fail")]
+            ProcessFileError::IntendFail => false,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -58,6 +139,15 @@ impl<E: StdError + Debug + Send + Sync + 'static> AggregateError<E> {
         }
         res
     }
+
+    pub fn iter(&self) -> impl Iterator<Item = &E> {
+        let mut node = Some(&self.root);
+        std::iter::from_fn(move || {
+            let current = node?;
+            node = current.next.as_deref();
+            Some(&current.error)
+        })
+    }
 }
 
 impl<E: StdError + Debug + Send + Sync + 'static> IntoIterator for AggregateError<E> {
@@ -153,4 +243,10 @@ mod tests {
         }
         "###);
     }
+
+    #[test]
+    fn test_wrangler_timeout() {
+        let err = ProcessFileError::WranglerTimeout(Duration::from_secs(600));
+        assert_eq!(err.to_string(), "wrangler timeout after 600s");
+    }
 }
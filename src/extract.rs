@@ -0,0 +1,416 @@
+use crate::errors::ProcessFileError;
+use serde_derive::Deserialize;
+use std::{
+    fs,
+    io::{self, Read, Seek},
+    path::{Component, Path, PathBuf},
+};
+use tar::{Archive, EntryType};
+use tracing::{debug, warn};
+use zip::ZipArchive;
+
+const DEFAULT_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+const DEFAULT_MAX_ENTRIES: usize = 100_000;
+
+/// Limits applied while unpacking an archive, so a malformed output can't fill the disk
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExtractLimits {
+    /// Maximum total uncompressed size of all files
+    pub max_bytes: u64,
+    pub max_entries: usize,
+}
+
+impl Default for ExtractLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: DEFAULT_MAX_BYTES,
+            max_entries: DEFAULT_MAX_ENTRIES,
+        }
+    }
+}
+
+/// Reason why an archive is rejected
+#[derive(Debug, thiserror::Error)]
+pub enum ExtractError {
+    #[error("uncompressed size exceeds {limit} bytes")]
+    TooLarge { limit: u64 },
+    #[error("more than {limit} entries")]
+    TooManyEntries { limit: usize },
+    #[error("unsafe path {}", .0.display())]
+    UnsafePath(PathBuf),
+    #[error("link {} points outside to {}", .path.display(), .target.display())]
+    UnsafeLink { path: PathBuf, target: PathBuf },
+    #[error("unsupported entry {} of type {kind}", .path.display())]
+    UnsupportedEntry { path: PathBuf, kind: String },
+    #[error("invalid zip archive")]
+    Zip(#[from] zip::result::ZipError),
+}
+
+/// Unpack a tar archive into `dst` entry by entry, rejecting it as soon as a limit is hit
+///
+/// Files already written are left in `dst`, which is expected to be a temp dir removed by the caller.
+pub fn unpack<R: Read>(
+    archive: &mut Archive<R>,
+    dst: &Path,
+    limits: &ExtractLimits,
+) -> Result<(), ProcessFileError> {
+    let mut total_bytes = 0u64;
+
+    for (index, entry) in archive.entries()?.enumerate() {
+        if index >= limits.max_entries {
+            return Err(ExtractError::TooManyEntries {
+                limit: limits.max_entries,
+            }
+            .into());
+        }
+
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if !is_relative_inside(&path) {
+            return Err(ExtractError::UnsafePath(path).into());
+        }
+
+        let kind = entry.header().entry_type();
+        match kind {
+            EntryType::Regular | EntryType::Continuous | EntryType::Directory => (),
+            EntryType::Symlink | EntryType::Link => {
+                let target = entry
+                    .link_name()?
+                    .map(|target| target.into_owned())
+                    .unwrap_or_default();
+                // symlinks are relative to the entry, hard links to the archive root
+                let (resolved, dir) = match kind {
+                    EntryType::Symlink => {
+                        let dir = path.parent().unwrap_or(Path::new(""));
+                        (dir.join(&target), dir)
+                    }
+                    _ => (target.clone(), target.as_path()),
+                };
+                // the lexical check only holds when no link is followed on the way
+                if !is_relative_inside(&resolved) || goes_through_link(dst, dir) {
+                    return Err(ExtractError::UnsafeLink { path, target }.into());
+                }
+            }
+            // pax global headers carry no file
+            EntryType::XGlobalHeader => continue,
+            kind => {
+                return Err(ExtractError::UnsupportedEntry {
+                    path,
+                    kind: format!("{kind:?}"),
+                }
+                .into())
+            }
+        }
+
+        // the declared size is exactly what will be read for the entry
+        total_bytes = total_bytes.saturating_add(entry.header().size()?);
+        if total_bytes > limits.max_bytes {
+            return Err(ExtractError::TooLarge {
+                limit: limits.max_bytes,
+            }
+            .into());
+        }
+
+        if !entry.unpack_in(dst)? {
+            warn!(path = %path.display(), "skip entry outside of the destination");
+        }
+    }
+
+    debug!(total_bytes, "archive extracted");
+    Ok(())
+}
+
+/// Unpack a zip archive into `dst` with the same limits as [`unpack`]
+///
+/// Unlike tar, the declared sizes of a zip can't be trusted, so the written bytes are counted instead.
+pub fn unpack_zip<R: Read + Seek>(
+    archive: R,
+    dst: &Path,
+    limits: &ExtractLimits,
+) -> Result<(), ProcessFileError> {
+    let mut archive = ZipArchive::new(archive).map_err(ExtractError::from)?;
+    if archive.len() > limits.max_entries {
+        return Err(ExtractError::TooManyEntries {
+            limit: limits.max_entries,
+        }
+        .into());
+    }
+
+    let root = fs::canonicalize(dst)?;
+    let mut total_bytes = 0u64;
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index).map_err(ExtractError::from)?;
+        let path = PathBuf::from(file.name());
+        if !is_relative_inside(&path) {
+            return Err(ExtractError::UnsafePath(path).into());
+        }
+        // sites are built with plain files, a link is only a way to write outside `dst`
+        if file.is_symlink() {
+            return Err(ExtractError::UnsupportedEntry {
+                path,
+                kind: "symlink".to_owned(),
+            }
+            .into());
+        }
+        let out_path = root.join(&path);
+
+        if file.is_dir() {
+            fs::create_dir_all(&out_path)?;
+            check_real_dir(&root, &out_path, &path)?;
+            continue;
+        }
+        if let Some(parent) = out_path.parent() {
+            fs::create_dir_all(parent)?;
+            check_real_dir(&root, parent, &path)?;
+        }
+
+        let remaining = limits.max_bytes - total_bytes;
+        let mut out = fs::File::create(&out_path)?;
+        // one more byte than allowed to detect the overflow
+        let written = io::copy(&mut (&mut file).take(remaining + 1), &mut out)?;
+        total_bytes += written;
+        if total_bytes > limits.max_bytes {
+            return Err(ExtractError::TooLarge {
+                limit: limits.max_bytes,
+            }
+            .into());
+        }
+    }
+
+    debug!(total_bytes, "zip archive extracted");
+    Ok(())
+}
+
+/// Whether `path` under `dst` goes through a link already unpacked, the path included
+fn goes_through_link(dst: &Path, path: &Path) -> bool {
+    let mut current = dst.to_owned();
+    path.components().any(|component| {
+        current.push(component);
+        fs::symlink_metadata(&current).is_ok_and(|metadata| metadata.file_type().is_symlink())
+    })
+}
+
+/// Fail unless `dir` is still inside the canonical `root` once its links are resolved on disk
+fn check_real_dir(root: &Path, dir: &Path, entry: &Path) -> Result<(), ProcessFileError> {
+    if !fs::canonicalize(dir)?.starts_with(root) {
+        return Err(ExtractError::UnsafePath(entry.to_owned()).into());
+    }
+    Ok(())
+}
+
+/// Whether the path stays inside the root after resolving `..`, without touching the file system
+pub(crate) fn is_relative_inside(path: &Path) -> bool {
+    let mut depth = 0usize;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => (),
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(parent) => depth = parent,
+                None => return false,
+            },
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tar::{Builder, Header};
+    use tempfile::tempdir;
+
+    fn header(path: &str, kind: EntryType, size: u64) -> Header {
+        let mut header = Header::new_gnu();
+        // write the name directly, `set_path` refuses unsafe paths
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_entry_type(kind);
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_cksum();
+        header
+    }
+
+    fn archive(entries: &[(Header, &[u8])]) -> Archive<std::io::Cursor<Vec<u8>>> {
+        let mut builder = Builder::new(Vec::new());
+        for (header, data) in entries {
+            builder.append(header, *data).unwrap();
+        }
+        Archive::new(std::io::Cursor::new(builder.into_inner().unwrap()))
+    }
+
+    fn unpack_with(
+        entries: &[(Header, &[u8])],
+        limits: ExtractLimits,
+    ) -> Result<(), ProcessFileError> {
+        let dir = tempdir().unwrap();
+        unpack(&mut archive(entries), dir.path(), &limits)
+    }
+
+    #[test]
+    fn test_unpack() {
+        let dir = tempdir().unwrap();
+        let mut link = header("public/latest.html", EntryType::Symlink, 0);
+        link.set_link_name("../index.html").unwrap();
+        link.set_cksum();
+        let entries = [
+            (header("public/", EntryType::Directory, 0), &b""[..]),
+            (header("index.html", EntryType::Regular, 5), &b"hello"[..]),
+            (link, &b""[..]),
+        ];
+
+        unpack(
+            &mut archive(&entries),
+            dir.path(),
+            &ExtractLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("index.html")).unwrap(),
+            "hello"
+        );
+    }
+
+    #[test]
+    fn test_reject_limits() {
+        let entries = [
+            (header("a.html", EntryType::Regular, 5), &b"hello"[..]),
+            (header("b.html", EntryType::Regular, 5), &b"hello"[..]),
+        ];
+
+        let res = unpack_with(
+            &entries,
+            ExtractLimits {
+                max_bytes: 8,
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            res,
+            Err(ProcessFileError::Extract(ExtractError::TooLarge {
+                limit: 8
+            }))
+        ));
+
+        let res = unpack_with(
+            &entries,
+            ExtractLimits {
+                max_entries: 1,
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            res,
+            Err(ProcessFileError::Extract(ExtractError::TooManyEntries {
+                limit: 1
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_reject_unsafe_entries() {
+        let limits = ExtractLimits::default();
+
+        let res = unpack_with(
+            &[(header("../escape.html", EntryType::Regular, 0), b"")],
+            limits,
+        );
+        assert!(matches!(
+            res,
+            Err(ProcessFileError::Extract(ExtractError::UnsafePath(_)))
+        ));
+
+        let res = unpack_with(
+            &[(header("/etc/passwd", EntryType::Regular, 0), b"")],
+            limits,
+        );
+        assert!(matches!(
+            res,
+            Err(ProcessFileError::Extract(ExtractError::UnsafePath(_)))
+        ));
+
+        let mut link = header("public/passwd", EntryType::Symlink, 0);
+        link.set_link_name("../../etc/passwd").unwrap();
+        link.set_cksum();
+        let res = unpack_with(&[(link, b"")], limits);
+        assert!(matches!(
+            res,
+            Err(ProcessFileError::Extract(ExtractError::UnsafeLink { .. }))
+        ));
+
+        let mut link = header("passwd", EntryType::Link, 0);
+        link.set_link_name("/etc/passwd").unwrap();
+        link.set_cksum();
+        let res = unpack_with(&[(link, b"")], limits);
+        assert!(matches!(
+            res,
+            Err(ProcessFileError::Extract(ExtractError::UnsafeLink { .. }))
+        ));
+
+        let res = unpack_with(&[(header("null", EntryType::Char, 0), b"")], limits);
+        assert!(matches!(
+            res,
+            Err(ProcessFileError::Extract(
+                ExtractError::UnsupportedEntry { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_reject_chained_links() {
+        // each link stays inside on its own, the second one is resolved through the first
+        let mut first = header("d/l", EntryType::Symlink, 0);
+        first.set_link_name("..").unwrap();
+        first.set_cksum();
+        let mut second = header("d/l/e", EntryType::Symlink, 0);
+        second.set_link_name("..").unwrap();
+        second.set_cksum();
+        let entries = [
+            (header("d/", EntryType::Directory, 0), &b""[..]),
+            (first, &b""[..]),
+            (second, &b""[..]),
+            (header("d/l/e/pwned", EntryType::Regular, 5), &b"pwned"[..]),
+        ];
+
+        let parent = tempdir().unwrap();
+        let dst = parent.path().join("dst");
+        fs::create_dir(&dst).unwrap();
+        let res = unpack(&mut archive(&entries), &dst, &ExtractLimits::default());
+        assert!(matches!(
+            res,
+            Err(ProcessFileError::Extract(ExtractError::UnsafeLink { .. }))
+        ));
+        assert!(!parent.path().join("pwned").exists());
+        assert!(fs::symlink_metadata(parent.path().join("e")).is_err());
+    }
+
+    #[test]
+    fn test_reject_zip_links() {
+        use std::io::Write;
+
+        // each link is harmless on its own, together they lead out of the destination
+        let mut writer = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default();
+        writer.add_directory("d", options).unwrap();
+        writer.add_symlink("d/l", "..", options).unwrap();
+        writer.add_symlink("d/l/e", "..", options).unwrap();
+        writer.start_file("d/l/e/pwned", options).unwrap();
+        writer.write_all(b"pwned").unwrap();
+        let archive = writer.finish().unwrap();
+
+        let parent = tempdir().unwrap();
+        let dst = parent.path().join("dst");
+        fs::create_dir(&dst).unwrap();
+        let res = unpack_zip(archive, &dst, &ExtractLimits::default());
+        assert!(matches!(
+            res,
+            Err(ProcessFileError::Extract(
+                ExtractError::UnsupportedEntry { .. }
+            ))
+        ));
+        assert!(!parent.path().join("pwned").exists());
+        assert!(fs::symlink_metadata(dst.join("d/l")).is_err());
+    }
+}
@@ -0,0 +1,253 @@
+use brotli::enc::BrotliEncoderParams;
+use flate2::{write::GzEncoder, Compression};
+use serde_derive::Deserialize;
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    str::FromStr,
+};
+
+/// Files smaller than this are not worth a compressed variant
+const MIN_COMPRESS_SIZE: u64 = 1024;
+
+/// Headers of the assets uploaded to R2, so the CDN in front of it can cache them
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeaderPolicy {
+    /// `Cache-Control` of the files with a content hash in their name
+    pub immutable_cache_control: String,
+    /// `Cache-Control` of the other files, which may change between releases
+    pub cache_control: String,
+    /// Variants uploaded next to text files as `{key}.br` / `{key}.gz` with `Content-Encoding`
+    pub precompress: Vec<Encoding>,
+    /// `Cache-Control` by file extension without the dot, e.g. `json`, over the rules above
+    pub overrides: BTreeMap<String, String>,
+}
+
+impl Default for HeaderPolicy {
+    fn default() -> Self {
+        Self {
+            immutable_cache_control: "public, max-age=31536000, immutable".to_owned(),
+            cache_control: "public, max-age=60".to_owned(),
+            precompress: Vec::new(),
+            overrides: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Br,
+    Gzip,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "br" => Ok(Encoding::Br),
+            "gzip" => Ok(Encoding::Gzip),
+            _ => Err(format!("unknown encoding {value}, expect br or gzip")),
+        }
+    }
+}
+
+impl Encoding {
+    /// Value of the `Content-Encoding` header
+    pub fn content_encoding(self) -> &'static str {
+        match self {
+            Encoding::Br => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    pub fn variant_key(self, key: &str) -> String {
+        match self {
+            Encoding::Br => format!("{key}.br"),
+            Encoding::Gzip => format!("{key}.gz"),
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Br => {
+                let mut output = Vec::new();
+                let params = BrotliEncoderParams::default();
+                brotli::BrotliCompress(&mut &data[..], &mut output, &params)?;
+                Ok(output)
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+impl HeaderPolicy {
+    pub fn cache_control(&self, key: &str) -> &str {
+        let file_name = key.rsplit('/').next().unwrap_or(key);
+        let extension = file_name.rsplit_once('.').map(|(_, extension)| extension);
+        if let Some(cache_control) = extension.and_then(|extension| self.overrides.get(extension)) {
+            return cache_control;
+        }
+
+        if is_hashed(file_name) {
+            &self.immutable_cache_control
+        } else {
+            &self.cache_control
+        }
+    }
+
+    /// Hash of the rules behind [`Self::cache_control`], it changes when the `Cache-Control` of an
+    /// already uploaded object may have to
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = blake3::Hasher::new();
+        for value in [&self.immutable_cache_control, &self.cache_control] {
+            hasher.update(value.as_bytes()).update(b"\n");
+        }
+        for (extension, cache_control) in &self.overrides {
+            hasher
+                .update(extension.as_bytes())
+                .update(b"=")
+                .update(cache_control.as_bytes())
+                .update(b"\n");
+        }
+        hasher.finalize().to_hex().to_string()
+    }
+
+    /// Encodings of the variants to upload, none for binary or tiny files
+    pub fn encodings(&self, content_type: &str, size: u64) -> &[Encoding] {
+        if size >= MIN_COMPRESS_SIZE && is_compressible(content_type) {
+            &self.precompress
+        } else {
+            &[]
+        }
+    }
+}
+
+/// Whether the name carries a content hash, as `entry.DqVwGhK2.js` (Nuxt 3) or `4a3f2c1.js` (Nuxt 2)
+fn is_hashed(file_name: &str) -> bool {
+    let mut segments = file_name.split('.').collect::<Vec<_>>();
+    // extension
+    segments.pop();
+
+    match segments.as_slice() {
+        [] => false,
+        [stem] => stem.len() >= 7 && stem.bytes().all(|byte| byte.is_ascii_hexdigit()),
+        [_, rest @ ..] => rest.iter().any(|segment| is_hash(segment)),
+    }
+}
+
+fn is_hash(segment: &str) -> bool {
+    (7..=16).contains(&segment.len())
+        && segment
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-')
+        // tell hashes apart from words like `default`
+        && segment
+            .bytes()
+            .any(|byte| byte.is_ascii_digit() || byte.is_ascii_uppercase())
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+        || matches!(
+            content_type,
+            "application/javascript"
+                | "application/json"
+                | "application/manifest+json"
+                | "application/wasm"
+                | "application/xml"
+                | "image/svg+xml"
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_cache_control() {
+        let mut policy = HeaderPolicy::default();
+        let immutable = policy.immutable_cache_control.clone();
+        let short = policy.cache_control.clone();
+
+        assert_eq!(
+            policy.cache_control("P1/_nuxt/entry.DqVwGhK2.js"),
+            immutable
+        );
+        assert_eq!(
+            policy.cache_control("P1/_nuxt/default.CZ7Kb2Nq.css"),
+            immutable
+        );
+        assert_eq!(
+            policy.cache_control("P1/_nuxt/pages/index.1a2b3c4.js"),
+            immutable
+        );
+        assert_eq!(policy.cache_control("P1/_nuxt/4a3f2c1d.js"), immutable);
+        assert_eq!(policy.cache_control("P1/_nuxt/builds/latest.json"), short);
+        assert_eq!(policy.cache_control("P1/_nuxt/index.default.js"), short);
+        assert_eq!(policy.cache_control("P1/_nuxt/LICENSE"), short);
+
+        policy
+            .overrides
+            .insert("css".to_owned(), "no-cache".to_owned());
+        assert_eq!(
+            policy.cache_control("P1/_nuxt/default.CZ7Kb2Nq.css"),
+            "no-cache"
+        );
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let mut policy = HeaderPolicy::default();
+        let fingerprint = policy.fingerprint();
+        assert_eq!(fingerprint, HeaderPolicy::default().fingerprint());
+
+        // the variants keep their headers, only a new precompress setting adds some
+        policy.precompress.push(Encoding::Br);
+        assert_eq!(policy.fingerprint(), fingerprint);
+
+        policy
+            .overrides
+            .insert("json".to_owned(), "no-cache".to_owned());
+        assert_ne!(policy.fingerprint(), fingerprint);
+    }
+
+    #[test]
+    fn test_precompress() {
+        let policy = HeaderPolicy {
+            precompress: vec![Encoding::Br, Encoding::Gzip],
+            ..Default::default()
+        };
+        assert!(policy.encodings("image/png", 4096).is_empty());
+        assert!(policy.encodings("application/javascript", 16).is_empty());
+        assert_eq!(policy.encodings("text/css", 4096).len(), 2);
+
+        let data = "console.log('hello');".repeat(100);
+
+        let compressed = Encoding::Br.compress(data.as_bytes()).unwrap();
+        let mut decompressed = String::new();
+        brotli::Decompressor::new(&compressed[..], 4096)
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+
+        let compressed = Encoding::Gzip.compress(data.as_bytes()).unwrap();
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+
+        assert_eq!(
+            Encoding::Gzip.variant_key("P1/_nuxt/a.js"),
+            "P1/_nuxt/a.js.gz"
+        );
+    }
+}
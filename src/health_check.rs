@@ -1,4 +1,4 @@
-use crate::{constants::SENTRY_CRON_CHECK_URL, http::build_client};
+use crate::http::build_client;
 use once_cell::sync::Lazy;
 use reqwest::StatusCode;
 use reqwest_middleware::ClientWithMiddleware;
@@ -12,9 +12,9 @@ static CLIENT: Lazy<ClientWithMiddleware> =
 pub struct HealthCheckInner(Instant);
 
 #[instrument]
-pub async fn start_job(check_in_id: &str) -> Option<HealthCheckInner> {
+pub async fn start_job(url: &str, check_in_id: &str) -> Option<HealthCheckInner> {
     match CLIENT
-        .get(SENTRY_CRON_CHECK_URL)
+        .get(url)
         .query(&[("status", "in_progress"), ("check_in_id", check_in_id)])
         .send()
         .await
@@ -34,11 +34,11 @@ pub async fn start_job(check_in_id: &str) -> Option<HealthCheckInner> {
 }
 
 #[instrument]
-pub async fn end_job(check_in_id: &str, HealthCheckInner(instant): HealthCheckInner) {
+pub async fn end_job(url: &str, check_in_id: &str, HealthCheckInner(instant): HealthCheckInner) {
     let duration = instant.elapsed();
     debug!(?duration, "finish check in {check_in_id}");
     match CLIENT
-        .get(SENTRY_CRON_CHECK_URL)
+        .get(url)
         .query(&[("status", "ok"), ("check_in_id", check_in_id)])
         .send()
         .await
@@ -57,25 +57,25 @@ pub async fn end_job(check_in_id: &str, HealthCheckInner(instant): HealthCheckIn
 
 #[must_use = "Must call finish to report success"]
 #[derive(Debug)]
-pub struct HealthCheck(String, Option<HealthCheckInner>);
+pub struct HealthCheck<'a>(&'a str, String, Option<HealthCheckInner>);
 
-impl HealthCheck {
+impl<'a> HealthCheck<'a> {
     #[instrument]
-    pub async fn start() -> Self {
+    pub async fn start(url: &'a str) -> Self {
         let check_in_id = uuid::Uuid::new_v4().to_string();
-        let inner = start_job(&check_in_id).await;
+        let inner = start_job(url, &check_in_id).await;
         debug!("start check in {:?}", inner.as_ref().map(|inner| &inner.0));
-        Self(check_in_id, inner)
+        Self(url, check_in_id, inner)
     }
 
     #[instrument]
     pub async fn finish(self) {
-        let HealthCheck(check_in_id, Some(inner)) = self else {
+        let HealthCheck(url, check_in_id, Some(inner)) = self else {
             debug!("finish check in without id");
             return;
         };
 
-        end_job(&check_in_id, inner).await
+        end_job(url, &check_in_id, inner).await
     }
 }
 
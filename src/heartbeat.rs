@@ -1,23 +1,29 @@
 use aws_sdk_sqs::Client;
-use futures::FutureExt;
-use std::{future::Future, mem, pin::Pin, time::Duration};
-use tokio::{task, time};
+use std::{future::Future, time::Duration};
+use tokio::time;
 
-const INITIAL_TIMEOUT: i32 = 240;
 const PREPARE_TIME: u64 = 10;
 
 pub struct HeartBeat<'a> {
     client: &'a Client,
     queue_url: &'a str,
     message_handle: &'a str,
+    initial_timeout: i32,
 }
 
 impl<'a> HeartBeat<'a> {
-    pub fn new(client: &'a Client, queue_url: &'a str, message_handle: &'a str) -> Self {
+    /// `initial_timeout` is the visibility timeout of the queue, in seconds
+    pub fn new(
+        client: &'a Client,
+        queue_url: &'a str,
+        message_handle: &'a str,
+        initial_timeout: i32,
+    ) -> Self {
         Self {
             client,
             queue_url,
             message_handle,
+            initial_timeout,
         }
     }
 
@@ -28,19 +34,12 @@ impl<'a> HeartBeat<'a> {
     {
         let mut timeout_extending_count = 0;
 
-        let mut current_timeout = INITIAL_TIMEOUT;
+        let mut current_timeout = self.initial_timeout;
 
-        // Safety: we are sure that we will join the task
-        let worker = unsafe {
-            mem::transmute::<_, Pin<Box<dyn Future<Output = ()> + Send + 'static>>>(
-                async move {
-                    f().await;
-                }
-                .boxed(),
-            )
-        };
-        // move task to background
-        let mut join_handle = task::spawn(worker);
+        // polled in place rather than spawned, so the worker never outlives the borrows it holds
+        // and is dropped along with this future when the deployment is aborted
+        let worker = f();
+        tokio::pin!(worker);
 
         loop {
             tokio::select! {
@@ -68,9 +67,12 @@ impl<'a> HeartBeat<'a> {
                     self.extend_timeout().await;
                     current_timeout = 60;
                 }
-                _ = &mut join_handle => break,
+                _ = &mut worker => return,
             }
         }
+
+        // stop extending, the worker still has to finish before its borrows go away
+        worker.await;
     }
 
     async fn extend_timeout(&self) {
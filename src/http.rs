@@ -20,11 +20,10 @@ pub(crate) fn build_client(
         .expect("Fail to init http client");
 
     let retry_policy = ExponentialBackoff::builder().build_with_max_retries(3);
-    let client = ClientBuilder::new(client)
+    ClientBuilder::new(client)
         // Trace HTTP requests. See the tracing crate to make use of these traces.
         .with(TracingMiddleware::default())
         // Retry failed requests.
         .with(RetryTransientMiddleware::new_with_policy(retry_policy))
-        .build();
-    client
+        .build()
 }
@@ -0,0 +1,133 @@
+use crate::types::DeployMeta;
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+
+/// Expected size and checksum of an archive, declared by the generator in the deploy meta
+#[derive(Debug, Clone, Default)]
+pub struct Integrity {
+    pub size: Option<u64>,
+    /// Hex encoded SHA-256 of the archive
+    pub sha256: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IntegrityError {
+    #[error("expect {expected} bytes, got {actual} bytes")]
+    SizeMismatch { expected: u64, actual: u64 },
+    #[error("expect sha256 {expected}, got {actual}")]
+    ChecksumMismatch { expected: String, actual: String },
+}
+
+impl Integrity {
+    pub fn from_meta(meta: &DeployMeta) -> Self {
+        Self {
+            size: meta.size,
+            sha256: meta.sha256.clone(),
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.size.is_none() && self.sha256.is_none()
+    }
+
+    pub fn verify(&self, size: u64, sha256: &str) -> Result<(), IntegrityError> {
+        if let Some(expected) = self.size {
+            if expected != size {
+                return Err(IntegrityError::SizeMismatch {
+                    expected,
+                    actual: size,
+                });
+            }
+        }
+
+        if let Some(expected) = self.sha256.as_deref() {
+            if !expected.eq_ignore_ascii_case(sha256) {
+                return Err(IntegrityError::ChecksumMismatch {
+                    expected: expected.to_owned(),
+                    actual: sha256.to_owned(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reader which hashes and counts everything read through it
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    /// Bytes read so far
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Read the rest of the stream up to `limit` bytes in total, e.g. the padding after the end
+    /// of a tar, and return the size and hex encoded SHA-256
+    pub fn finish(mut self, limit: u64) -> io::Result<(u64, String)> {
+        let remaining = limit.saturating_sub(self.size);
+        io::copy(&mut (&mut self).take(remaining), &mut io::sink())?;
+        Ok((self.size, format!("{:x}", self.hasher.finalize())))
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.size += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let mut reader = HashingReader::new(&b"hello"[..]);
+        let mut head = [0; 2];
+        reader.read_exact(&mut head).unwrap();
+        let (size, sha256) = reader.finish(u64::MAX).unwrap();
+        assert_eq!(size, 5);
+
+        let integrity = Integrity {
+            size: Some(5),
+            sha256: Some(
+                "2CF24DBA5FB0A30E26E83B2AC5B9E29E1B161E5C1FA7425E73043362938B9824".to_owned(),
+            ),
+        };
+        integrity.verify(size, &sha256).unwrap();
+
+        assert!(matches!(
+            integrity.verify(4, &sha256),
+            Err(IntegrityError::SizeMismatch {
+                expected: 5,
+                actual: 4
+            })
+        ));
+        let integrity = Integrity {
+            sha256: Some("00".to_owned()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            integrity.verify(size, &sha256),
+            Err(IntegrityError::ChecksumMismatch { .. })
+        ));
+    }
+}
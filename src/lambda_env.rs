@@ -3,7 +3,7 @@ use sentry::protocol::Context;
 use serde_json::Value;
 use std::env;
 
-pub static LAMBDA_ENV: Lazy<Option<LambdaEnv>> = Lazy::new(|| LambdaEnv::from_env());
+pub static LAMBDA_ENV: Lazy<Option<LambdaEnv>> = Lazy::new(LambdaEnv::from_env);
 
 // Modify from https://github.com/awslabs/aws-lambda-rust-runtime/blob/master/lambda-runtime/src/lib.rs#L33
 // We don't want it to panic when the environment variable is not set.
@@ -34,9 +34,7 @@ impl LambdaEnv {
             log_stream: env::var("AWS_LAMBDA_LOG_STREAM_NAME").ok(),
             log_group: env::var("AWS_LAMBDA_LOG_GROUP_NAME").ok(),
         };
-        if conf.function_name.is_none() {
-            return None;
-        }
+        conf.function_name.as_ref()?;
         Some(conf)
     }
 }
@@ -49,36 +47,33 @@ pub fn sentry_lambda() {
                 "function_name".into(),
                 env.function_name
                     .clone()
-                    .map(|v| Value::from(v))
+                    .map(Value::from)
                     .unwrap_or(Value::Null),
             );
             map.insert(
                 "memory".into(),
-                env.memory.map(|v| Value::from(v)).unwrap_or(Value::Null),
+                env.memory.map(Value::from).unwrap_or(Value::Null),
             );
             map.insert(
                 "version".into(),
-                env.version
-                    .clone()
-                    .map(|v| Value::from(v))
-                    .unwrap_or(Value::Null),
+                env.version.clone().map(Value::from).unwrap_or(Value::Null),
             );
             map.insert(
                 "log_stream".into(),
                 env.log_stream
                     .clone()
-                    .map(|v| Value::from(v))
+                    .map(Value::from)
                     .unwrap_or(Value::Null),
             );
             map.insert(
                 "log_group".into(),
                 env.log_group
                     .clone()
-                    .map(|v| Value::from(v))
+                    .map(Value::from)
                     .unwrap_or(Value::Null),
             );
 
-            scope.set_context("lambda_env".into(), Context::Other(map));
+            scope.set_context("lambda_env", Context::Other(map));
         }
     });
 }
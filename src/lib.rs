@@ -1,20 +1,34 @@
 mod api;
+mod asset_gc;
 pub mod bootstrap;
+#[allow(dead_code)]
 mod check_version;
+pub mod circuit_breaker;
+mod claim;
 mod clean_files;
-mod constants;
+pub mod config;
+mod deploy_target;
+mod direct_upload;
+pub mod environment;
 mod errors;
+mod extract;
+mod header_policy;
 pub mod health_check;
 pub mod heartbeat;
 mod http;
+mod integrity;
 pub mod lambda_env;
 pub mod metric;
+pub mod notification;
 mod nuxt_variant;
+pub mod pages;
 mod put_directory;
 mod retry;
 pub mod s3_handler;
+mod signature;
 mod sitemap;
+pub mod sqs_error;
 pub mod test_event;
-mod types;
+pub mod types;
 mod verify_site;
 mod wrangler;
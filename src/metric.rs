@@ -5,7 +5,35 @@ use aws_sdk_cloudwatch::{
 use std::time::Instant;
 use tracing::error;
 
-use crate::types::{DeployMeta, FileSummary};
+use crate::{
+    sqs_error::SqsErrorKind,
+    types::{DeployMeta, FileSummary},
+};
+
+/// Count SQS errors by kind, so transient errors are visible even when the service recovers
+pub async fn sqs_error(client: &Client, kind: SqsErrorKind) {
+    if let Err(err) = client
+        .put_metric_data()
+        .namespace("Deployer")
+        .metric_data(
+            MetricDatum::builder()
+                .metric_name("sqs_error")
+                .value(1.0)
+                .unit(StandardUnit::Count)
+                .dimensions(
+                    Dimension::builder()
+                        .name("kind")
+                        .value(kind.as_ref())
+                        .build(),
+                )
+                .build(),
+        )
+        .send()
+        .await
+    {
+        error!(?err, "Fail to send metric");
+    }
+}
 
 pub fn start<'a>(client: &'a Client) -> DurationMetricGuard<'a> {
     DurationMetricGuard::new(client)
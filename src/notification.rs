@@ -0,0 +1,203 @@
+use crate::test_event::TestEvent;
+use aws_lambda_events::s3::{S3Bucket, S3Entity, S3Event, S3EventRecord, S3Object};
+use serde::de::Error as _;
+use serde_derive::Deserialize;
+use serde_json::Value;
+use std::str::FromStr;
+
+/// Message received from the queue, after unwrapping SNS and EventBridge envelopes
+#[derive(Debug)]
+pub enum Notification {
+    S3(S3Event),
+    Test(TestEvent),
+    /// Valid notification which is not meant for the deployer, e.g. `Object Deleted`
+    Ignored(String),
+}
+
+/// SNS envelope when the bucket notification is fanned out through a topic
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SnsEnvelope {
+    message: String,
+}
+
+/// EventBridge event sent by S3, only the fields needed to locate the object
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct EventBridgeEvent {
+    detail_type: String,
+    source: String,
+    region: Option<String>,
+    detail: EventBridgeDetail,
+}
+
+#[derive(Debug, Deserialize)]
+struct EventBridgeDetail {
+    bucket: EventBridgeBucket,
+    object: EventBridgeObject,
+    reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EventBridgeBucket {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct EventBridgeObject {
+    key: String,
+    size: Option<i64>,
+    etag: Option<String>,
+}
+
+impl FromStr for Notification {
+    type Err = serde_json::Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let value = serde_json::from_str::<Value>(input)?;
+
+        if value.get("Records").is_some() {
+            return serde_json::from_value(value).map(Notification::S3);
+        }
+
+        if value.get("Type").and_then(Value::as_str) == Some("Notification") {
+            let envelope = serde_json::from_value::<SnsEnvelope>(value)?;
+            return Notification::from_str(&envelope.message);
+        }
+
+        if value.get("detail-type").is_some() {
+            let event = serde_json::from_value::<EventBridgeEvent>(value)?;
+            return event.try_into();
+        }
+
+        TestEvent::from_str(input).map(Notification::Test)
+    }
+}
+
+impl TryFrom<EventBridgeEvent> for Notification {
+    type Error = serde_json::Error;
+
+    fn try_from(event: EventBridgeEvent) -> Result<Self, Self::Error> {
+        if event.source != "aws.s3" {
+            return Err(serde_json::Error::custom(format!(
+                "unknown EventBridge source {}",
+                event.source
+            )));
+        }
+
+        if event.detail_type != "Object Created" {
+            return Ok(Notification::Ignored(event.detail_type));
+        }
+
+        let EventBridgeEvent {
+            region,
+            detail:
+                EventBridgeDetail {
+                    bucket,
+                    object,
+                    reason,
+                },
+            ..
+        } = event;
+
+        let record = S3EventRecord {
+            event_source: Some("aws:s3".to_owned()),
+            aws_region: region,
+            event_name: reason.map(|reason| format!("ObjectCreated:{reason}")),
+            s3: S3Entity {
+                bucket: S3Bucket {
+                    name: Some(bucket.name),
+                    ..Default::default()
+                },
+                object: S3Object {
+                    key: Some(object.key),
+                    size: object.size,
+                    e_tag: object.etag,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        Ok(Notification::S3(S3Event {
+            records: vec![record],
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static S3_EVENT: &str = r#"{"Records":[{"eventVersion":"2.1","eventSource":"aws:s3","awsRegion":"us-east-1","eventTime":"2024-01-01T00:00:00.000Z","eventName":"ObjectCreated:Put","userIdentity":{"principalId":"AWS:EXAMPLE"},"requestParameters":{"sourceIPAddress":"127.0.0.1"},"responseElements":{},"s3":{"s3SchemaVersion":"1.0","configurationId":"deploy","bucket":{"name":"storipress","ownerIdentity":{"principalId":"EXAMPLE"},"arn":"arn:aws:s3:::storipress"},"object":{"key":"P123/release.tar.br","size":1024,"eTag":"abc","sequencer":"0"}}}]}"#;
+
+    fn assert_record(notification: Notification) {
+        let Notification::S3(event) = notification else {
+            panic!("expect s3 event, got {notification:?}");
+        };
+        assert_eq!(event.records.len(), 1);
+        let object = &event.records[0].s3;
+        assert_eq!(object.bucket.name.as_deref(), Some("storipress"));
+        assert_eq!(object.object.key.as_deref(), Some("P123/release.tar.br"));
+    }
+
+    #[test]
+    fn test_parse_s3_event() {
+        assert_record(Notification::from_str(S3_EVENT).unwrap());
+    }
+
+    #[test]
+    fn test_parse_sns_envelope() {
+        let body = serde_json::json!({
+            "Type": "Notification",
+            "MessageId": "00000000-0000-0000-0000-000000000000",
+            "TopicArn": "arn:aws:sns:us-east-1:000000000000:deploy",
+            "Message": S3_EVENT,
+            "Timestamp": "2024-01-01T00:00:00.000Z",
+        })
+        .to_string();
+
+        assert_record(Notification::from_str(&body).unwrap());
+    }
+
+    #[test]
+    fn test_parse_event_bridge() {
+        let body = serde_json::json!({
+            "version": "0",
+            "id": "00000000-0000-0000-0000-000000000000",
+            "detail-type": "Object Created",
+            "source": "aws.s3",
+            "account": "000000000000",
+            "time": "2024-01-01T00:00:00Z",
+            "region": "us-east-1",
+            "resources": ["arn:aws:s3:::storipress"],
+            "detail": {
+                "version": "0",
+                "bucket": { "name": "storipress" },
+                "object": { "key": "P123/release.tar.br", "size": 1024, "etag": "abc" },
+                "reason": "PutObject"
+            }
+        });
+
+        assert_record(Notification::from_str(&body.to_string()).unwrap());
+
+        let mut deleted = body;
+        deleted["detail-type"] = "Object Deleted".into();
+        assert!(matches!(
+            Notification::from_str(&deleted.to_string()),
+            Ok(Notification::Ignored(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_test_event() {
+        let body = r#"{"Service":"Amazon S3","Event":"s3:TestEvent","Time":"2024-01-01T00:00:00.000Z","Bucket":"storipress","RequestId":"0","HostId":"0"}"#;
+        let Ok(Notification::Test(event)) = Notification::from_str(body) else {
+            panic!("expect test event");
+        };
+        assert!(event.is_storipress_bucket());
+
+        assert!(Notification::from_str(r#"{"foo":"bar"}"#).is_err());
+    }
+}
@@ -10,7 +10,7 @@ impl NuxtVariant {
     pub fn check_path(path: &Path) -> io::Result<Self> {
         match path.join("dist/nitro.json").metadata() {
             // when nitro.json is a file, we assume it's a worker
-            Ok(meta) if meta.is_file() => return Ok(NuxtVariant::Worker),
+            Ok(meta) if meta.is_file() => Ok(NuxtVariant::Worker),
             // when nitro.json is not a file, because it still have `dist` folder, we assume it's a worker
             Ok(meta) => {
                 sentry::capture_message(
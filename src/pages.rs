@@ -0,0 +1,78 @@
+use crate::{
+    config::Config, direct_upload::DirectUpload, errors::ProcessFileError, types::DeployMeta,
+    wrangler::Wrangler,
+};
+use serde_derive::Deserialize;
+use std::{path::Path, str::FromStr};
+use tracing::{info, instrument};
+
+/// Publish a built site to Cloudflare Pages
+pub(crate) trait PagesDeployer {
+    /// `deploy_path` is the directory to publish, relative to `site_root` or absolute
+    async fn deploy(
+        &self,
+        meta: &DeployMeta,
+        site_root: &Path,
+        deploy_path: &Path,
+    ) -> Result<DeploymentResult, ProcessFileError>;
+}
+
+/// Deployment created by a [`PagesDeployer`], fields are `None` when the backend doesn't tell
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DeploymentResult {
+    pub deployment_id: Option<String>,
+    pub url: Option<String>,
+    /// Files uploaded by this deployment
+    pub uploaded: Option<u32>,
+    /// Files already uploaded by a previous deployment
+    pub skipped: Option<u32>,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PagesBackend {
+    /// `wrangler pages deploy` run by Node
+    #[default]
+    Wrangler,
+    /// Pages Direct Upload API called from Rust, falls back to wrangler for sites it can't handle
+    DirectUpload,
+}
+
+impl FromStr for PagesBackend {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "wrangler" => Ok(PagesBackend::Wrangler),
+            "direct_upload" => Ok(PagesBackend::DirectUpload),
+            _ => Err(format!(
+                "unknown backend {value}, expect wrangler or direct_upload"
+            )),
+        }
+    }
+}
+
+/// Deploy with the configured backend
+#[instrument(err, skip(config, meta))]
+pub async fn deploy(
+    config: &Config,
+    meta: &DeployMeta,
+    site_root: &Path,
+    deploy_path: &Path,
+) -> Result<DeploymentResult, ProcessFileError> {
+    let wrangler = Wrangler::new(config);
+    let result = match config.pages.backend {
+        PagesBackend::Wrangler => wrangler.deploy(meta, site_root, deploy_path).await?,
+        PagesBackend::DirectUpload => {
+            let direct_upload = DirectUpload::new(config);
+            if let Some(reason) = DirectUpload::unsupported(&site_root.join(deploy_path)) {
+                info!(reason, "fall back to wrangler");
+                wrangler.deploy(meta, site_root, deploy_path).await?
+            } else {
+                direct_upload.deploy(meta, site_root, deploy_path).await?
+            }
+        }
+    };
+    info!(?result, "pages deployed");
+    Ok(result)
+}
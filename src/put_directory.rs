@@ -1,38 +1,133 @@
-use aws_sdk_s3::Client;
+use aws_sdk_s3::{
+    config::http::HttpResponse,
+    error::SdkError,
+    types::{CompletedMultipartUpload, CompletedPart, MetadataDirective},
+    Client,
+};
 use aws_smithy_types::byte_stream::ByteStream;
 use base64::{engine::general_purpose::STANDARD, Engine as _};
+use bytes::Bytes;
+use futures::{stream, StreamExt};
 use jwalk::WalkDir;
 use md5::{Digest, Md5};
-use std::{fs, path::Path};
-use tokio::{fs::File, io::AsyncReadExt};
-use tracing::{debug, error, info, instrument};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt},
+};
+use tracing::{debug, error, info, instrument, warn};
 
-use crate::{errors::AggregateError, retry::retry};
+use crate::{
+    config::Config,
+    errors::AggregateError,
+    header_policy::Encoding,
+    retry::{is_retryable_io, is_retryable_sdk, retry, Retryable},
+    types::UploadSummary,
+};
+
+/// Characters escaped in the key of `x-amz-copy-source`, the path separators are kept
+const COPY_SOURCE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
 
 #[derive(thiserror::Error, Debug)]
-#[error(transparent)]
 pub enum Error {
+    #[error(transparent)]
     Io(#[from] std::io::Error),
+    #[error(transparent)]
     ReadDir(#[from] jwalk::Error),
+    #[error(transparent)]
     StripPrefix(#[from] std::path::StripPrefixError),
-    AwsError(#[from] Box<dyn std::error::Error + Send + Sync>),
+    #[error("{source}")]
+    Aws {
+        /// Classified when the error is created, the source is type erased
+        retryable: bool,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// The parts of an upload without id could never be completed
+    #[error("no upload id for the multipart upload of {0}")]
+    MissingUploadId(String),
+    /// More files failed than `upload.max_failures` allows, each after its retries
+    #[error("fail to upload {} files", .failed.len())]
+    Upload {
+        failed: Vec<String>,
+        #[source]
+        source: AggregateError<Box<Error>>,
+    },
+}
+
+impl Retryable for Error {
+    fn is_retryable(&self) -> bool {
+        match self {
+            Error::Io(err) => is_retryable_io(err),
+            Error::Aws { retryable, .. } => *retryable,
+            // the failed files were retried already
+            Error::ReadDir(_)
+            | Error::StripPrefix(_)
+            | Error::MissingUploadId(_)
+            | Error::Upload { .. } => false,
+        }
+    }
+}
+
+/// Result of [`put_directory`]
+#[derive(Debug)]
+pub struct UploadReport {
+    pub summary: UploadSummary,
+    /// Every key of the directory, including the skipped ones
+    pub keys: Vec<String>,
+    /// Keys which still fail after their retries, at most `upload.max_failures`
+    pub failed: Vec<String>,
+}
+
+type Failure = ((PathBuf, String), AggregateError<Box<Error>>);
+
+/// Object already in the bucket, used to skip unchanged files
+#[derive(Debug)]
+struct RemoteObject {
+    /// Without the surrounding quotes
+    e_tag: String,
+    size: i64,
+}
+
+/// Headers of an uploaded object
+#[derive(Debug)]
+struct Headers<'a> {
+    content_type: &'a str,
+    cache_control: &'a str,
+    content_encoding: Option<&'static str>,
+}
+
+#[derive(Debug)]
+enum PutResult {
+    Uploaded,
+    Skipped,
 }
 
-#[instrument(err, skip(client, local_path), fields(local_path = %local_path.as_ref().display()))]
+/// Upload the files under `local_path` to `{key_prefix}/{relative path}`, or to the relative path
+/// when `key_prefix` is empty
+#[instrument(err, skip(client, config, local_path), fields(local_path = %local_path.as_ref().display()))]
 pub async fn put_directory(
     client: &Client,
+    config: &Config,
     bucket: &str,
     key_prefix: &str,
     local_path: impl AsRef<Path>,
-) -> Result<(), Error> {
+) -> Result<UploadReport, Error> {
     let local_path = local_path.as_ref();
     info!("start put directory");
-    let files = fs::read_dir(local_path)
-        .map_err(Error::from)?
-        .map(|entry| entry.map(|e| e.path()))
-        .collect::<Result<Vec<_>, _>>()
-        .map_err(Error::from)?;
-    debug!(local_path = %local_path.display(), ?files, "scan directory");
+
+    let mut objects = Vec::new();
+    let mut keys = Vec::new();
     for entry in WalkDir::new(local_path)
         .into_iter()
         .filter_map(|e| e.ok())
@@ -43,75 +138,772 @@ pub async fn put_directory(
             .strip_prefix(local_path)
             .map_err(Error::from)?
             .display();
-        let key = format!("{}/{}", key_prefix, relative_path);
+        let key = if key_prefix.is_empty() {
+            relative_path.to_string()
+        } else {
+            format!("{}/{}", key_prefix, relative_path)
+        };
 
-        let full_path = local_path.join(&path);
+        debug!(path = %path.display(), key, relative_path = %relative_path, "process file");
+        let size = fs::metadata(&path).map_err(Error::from)?.len();
+        let encodings = encodings(config, &content_type(&path), size);
+        keys.push(key.clone());
+        keys.extend(encodings.iter().map(|encoding| encoding.variant_key(&key)));
+        objects.push((path, key));
+    }
 
-        debug!(path = %path.display(), key, relative_path = %relative_path, full_path = %full_path.display(), "process file");
+    let policy_key = policy_key(key_prefix);
+    let policy = config.upload.headers.fingerprint();
+    let (remote, refresh) = if config.upload.sync {
+        let prefix = match key_prefix {
+            "" => String::new(),
+            key_prefix => format!("{key_prefix}/"),
+        };
+        let remote = match list_objects(client, bucket, &prefix).await {
+            Ok(remote) => remote,
+            Err(err) => {
+                // syncing only saves time, a full upload is still correct
+                warn!(?err, "fail to list objects, upload every file");
+                HashMap::new()
+            }
+        };
+        // skipped objects keep the headers they were uploaded with until the policy changes
+        let refresh = get_policy(client, bucket, &policy_key).await.as_ref() != Some(&policy);
+        (remote, refresh)
+    } else {
+        (HashMap::new(), false)
+    };
+    let remote = &remote;
 
-        if let Err(err) = put_object(client, &full_path, bucket, &key).await {
-            for err in err {
-                error!(?err, "fail to put object");
-                sentry::capture_error(&err);
+    // each request is retried under `config.retry`, a failed file is not worth another round
+    let (summary, failures) = put_objects(client, config, bucket, objects, remote, refresh).await;
+
+    let failed = failures
+        .iter()
+        .map(|((_, key), _)| key.clone())
+        .collect::<Vec<_>>();
+    info!(?summary, ?failed, refresh, "put directory finished");
+
+    // a failed refresh is retried by the next upload, as long as the policy is not recorded
+    if refresh && failed.is_empty() {
+        put_policy(client, bucket, &policy_key, policy).await;
+    }
+
+    if failed.len() > config.upload.max_failures {
+        let errors = failures
+            .into_iter()
+            .flat_map(|(_, err)| err)
+            .collect::<Vec<_>>();
+        return Err(Error::Upload {
+            failed,
+            source: AggregateError::from(errors),
+        });
+    }
+    if !failed.is_empty() {
+        sentry::capture_message(
+            &format!("Fail to upload {} files, below the threshold", failed.len()),
+            sentry::Level::Warning,
+        );
+    }
+
+    Ok(UploadReport {
+        summary,
+        keys,
+        failed,
+    })
+}
+
+/// Upload the objects concurrently, returning the failed ones with their errors
+async fn put_objects(
+    client: &Client,
+    config: &Config,
+    bucket: &str,
+    objects: Vec<(PathBuf, String)>,
+    remote: &HashMap<String, RemoteObject>,
+    refresh: bool,
+) -> (UploadSummary, Vec<Failure>) {
+    stream::iter(objects)
+        .map(|(full_path, key)| async move {
+            let res = put_object(client, config, bucket, &full_path, &key, remote, refresh).await;
+            ((full_path, key), res)
+        })
+        .buffer_unordered(config.upload.concurrency)
+        .fold(
+            (UploadSummary::default(), Vec::new()),
+            |(mut summary, mut failures), (object, res)| async move {
+                match res {
+                    Ok(PutResult::Uploaded) => summary.uploaded += 1,
+                    Ok(PutResult::Skipped) => summary.skipped += 1,
+                    Err(err) => {
+                        error!(?err, key = object.1, "fail to put object");
+                        summary.failed += 1;
+                        failures.push((object, err));
+                    }
+                }
+                (summary, failures)
+            },
+        )
+        .await
+}
+
+/// Objects under the prefix with their ETag, keyed by the object key
+#[instrument(err, skip(client))]
+async fn list_objects(
+    client: &Client,
+    bucket: &str,
+    prefix: &str,
+) -> Result<HashMap<String, RemoteObject>, Error> {
+    let mut objects = HashMap::new();
+    let mut pages = client
+        .list_objects_v2()
+        .bucket(bucket)
+        .prefix(prefix)
+        .into_paginator()
+        .send();
+
+    while let Some(page) = pages.next().await {
+        let page = page.map_err(aws_error)?;
+        for object in page.contents() {
+            if let (Some(key), Some(e_tag)) = (object.key(), object.e_tag()) {
+                let remote = RemoteObject {
+                    e_tag: e_tag.trim_matches('"').to_owned(),
+                    size: object.size().unwrap_or_default(),
+                };
+                objects.insert(key.to_owned(), remote);
             }
         }
     }
-    Ok(())
+
+    debug!(objects = objects.len(), "list existing objects");
+    Ok(objects)
 }
 
-#[instrument(err, skip(client))]
+#[instrument(err, skip(client, config, remote))]
 async fn put_object(
     client: &Client,
+    config: &Config,
+    bucket: &str,
     full_path: &Path,
+    key: &str,
+    remote: &HashMap<String, RemoteObject>,
+    refresh: bool,
+) -> Result<PutResult, AggregateError<Box<Error>>> {
+    let size = tokio::fs::metadata(full_path)
+        .await
+        .map_err(aggregate)?
+        .len();
+
+    let content_type = content_type(full_path);
+    let headers = Headers {
+        content_type: &content_type,
+        cache_control: config.upload.headers.cache_control(key),
+        content_encoding: None,
+    };
+    let existing = remote.get(key);
+
+    if size >= config.upload.multipart_threshold {
+        // hashing the parts reads the file one more time, only worth it when the size matches
+        if let Some(existing) = existing.filter(|existing| existing.size == size as i64) {
+            let e_tag = multipart_e_tag(full_path, config.upload.part_size)
+                .await
+                .map_err(aggregate)?;
+            if e_tag == existing.e_tag {
+                debug!(key, "unchanged, skip");
+                if refresh {
+                    refresh_headers(client, config, bucket, key, &headers).await?;
+                }
+                return Ok(PutResult::Skipped);
+            }
+        }
+        put_multipart(client, config, bucket, full_path, key, &headers).await?;
+        return Ok(PutResult::Uploaded);
+    }
+
+    // read once, the body and its checksum are reused by the retries and the variants
+    let body = Bytes::from(tokio::fs::read(full_path).await.map_err(aggregate)?);
+    let digest = Md5::digest(&body);
+    let encodings = encodings(config, &content_type, size);
+    let variants_exist = encodings
+        .iter()
+        .all(|encoding| remote.contains_key(&encoding.variant_key(key)));
+    if existing.is_some_and(|existing| existing.e_tag == hex(&digest)) && variants_exist {
+        debug!(key, "unchanged, skip");
+        if refresh {
+            refresh_headers(client, config, bucket, key, &headers).await?;
+            for &encoding in encodings {
+                let headers = Headers {
+                    content_encoding: Some(encoding.content_encoding()),
+                    ..headers
+                };
+                refresh_headers(client, config, bucket, &encoding.variant_key(key), &headers)
+                    .await?;
+            }
+        }
+        return Ok(PutResult::Skipped);
+    }
+
+    put_body(
+        client,
+        config,
+        bucket,
+        key,
+        &headers,
+        body.clone(),
+        STANDARD.encode(digest),
+    )
+    .await?;
+
+    for &encoding in encodings {
+        let data = body.clone();
+        let compressed = tokio::task::spawn_blocking(move || encoding.compress(&data))
+            .await
+            .map_err(std::io::Error::other)
+            .and_then(|res| res)
+            .map_err(aggregate)?;
+        let headers = Headers {
+            content_encoding: Some(encoding.content_encoding()),
+            ..headers
+        };
+        let content_md5 = content_md5(&compressed);
+        let key = encoding.variant_key(key);
+        put_body(
+            client,
+            config,
+            bucket,
+            &key,
+            &headers,
+            Bytes::from(compressed),
+            content_md5,
+        )
+        .await?;
+    }
+
+    Ok(PutResult::Uploaded)
+}
+
+async fn put_body(
+    client: &Client,
+    config: &Config,
     bucket: &str,
     key: &str,
+    headers: &Headers<'_>,
+    body: Bytes,
+    content_md5: String,
 ) -> Result<(), AggregateError<Box<Error>>> {
-    retry(|| async {
-        let stream = ByteStream::from_path(&full_path).await.map_err(|err| {
-            Error::from(Box::new(err) as Box<dyn std::error::Error + Send + Sync>)
-        })?;
+    debug!(content_md5, ?headers, key, "put_object");
 
-        let guess_mime = mime_guess::from_path(&full_path).first();
-        let content_type = guess_mime
-            .as_ref()
-            .map_or("application/octet-stream", |mime| mime.as_ref());
+    retry(&config.retry, || async {
+        client
+            .put_object()
+            .bucket(bucket)
+            .key(key)
+            .content_type(headers.content_type)
+            .cache_control(headers.cache_control)
+            .set_content_encoding(headers.content_encoding.map(ToOwned::to_owned))
+            .content_md5(&content_md5)
+            .body(ByteStream::from(body.clone()))
+            .send()
+            .await
+            .map_err(aws_error)?;
+        Ok(())
+    })
+    .await
+}
 
-        let content_md5 = calculate_md5(&full_path).await?;
+/// Replace the headers of an unchanged object when its `Cache-Control` is not the one of the
+/// header policy, which changed since the object was uploaded
+async fn refresh_headers(
+    client: &Client,
+    config: &Config,
+    bucket: &str,
+    key: &str,
+    headers: &Headers<'_>,
+) -> Result<(), AggregateError<Box<Error>>> {
+    retry(&config.retry, || async {
+        let object = client
+            .head_object()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(aws_error)?;
+        if object.cache_control() == Some(headers.cache_control) {
+            return Ok(());
+        }
 
-        debug!(content_md5, content_type, key, "put_object");
+        debug!(
+            key,
+            from = object.cache_control(),
+            ?headers,
+            "refresh headers"
+        );
+        // copying an object onto itself only replaces its metadata
+        client
+            .copy_object()
+            .bucket(bucket)
+            .key(key)
+            .copy_source(format!(
+                "{bucket}/{}",
+                utf8_percent_encode(key, COPY_SOURCE)
+            ))
+            .metadata_directive(MetadataDirective::Replace)
+            .content_type(headers.content_type)
+            .cache_control(headers.cache_control)
+            .set_content_encoding(headers.content_encoding.map(ToOwned::to_owned))
+            .send()
+            .await
+            .map_err(aws_error)?;
+        Ok(())
+    })
+    .await
+}
 
+/// Key of the header policy fingerprint of the objects under `key_prefix`, next to the prefix so
+/// listing the objects under it never returns the marker
+fn policy_key(key_prefix: &str) -> String {
+    format!("{key_prefix}.header-policy")
+}
+
+/// Fingerprint of the header policy the objects under the prefix were last uploaded with, none
+/// when it was never recorded or fails to read
+async fn get_policy(client: &Client, bucket: &str, key: &str) -> Option<String> {
+    let output = match client.get_object().bucket(bucket).key(key).send().await {
+        Ok(output) => output,
+        Err(err) => {
+            if !err
+                .as_service_error()
+                .is_some_and(|err| err.is_no_such_key())
+            {
+                warn!(?err, key, "fail to get header policy, refresh the headers");
+            }
+            return None;
+        }
+    };
+    match output.body.collect().await {
+        Ok(body) => String::from_utf8(body.to_vec()).ok(),
+        Err(err) => {
+            warn!(?err, key, "fail to read header policy, refresh the headers");
+            None
+        }
+    }
+}
+
+/// Record the header policy of the objects under the prefix, a failure only costs a refresh on the
+/// next upload
+async fn put_policy(client: &Client, bucket: &str, key: &str, policy: String) {
+    debug!(key, policy, "put header policy");
+    if let Err(err) = client
+        .put_object()
+        .bucket(bucket)
+        .key(key)
+        .content_type("text/plain")
+        .body(ByteStream::from(policy.into_bytes()))
+        .send()
+        .await
+    {
+        warn!(?err, key, "fail to put header policy");
+    }
+}
+
+/// Upload a large file part by part, so only one part is held in memory at a time
+#[instrument(err, skip(client, config))]
+async fn put_multipart(
+    client: &Client,
+    config: &Config,
+    bucket: &str,
+    full_path: &Path,
+    key: &str,
+    headers: &Headers<'_>,
+) -> Result<(), AggregateError<Box<Error>>> {
+    let upload = client
+        .create_multipart_upload()
+        .bucket(bucket)
+        .key(key)
+        .content_type(headers.content_type)
+        .cache_control(headers.cache_control)
+        .send()
+        .await
+        .map_err(|err| aggregate(aws_error(err)))?;
+    let upload_id = upload
+        .upload_id()
+        .ok_or_else(|| aggregate(Error::MissingUploadId(key.to_owned())))?;
+
+    let parts = match put_parts(client, config, bucket, full_path, key, upload_id).await {
+        Ok(parts) => parts,
+        Err(err) => {
+            // otherwise the uploaded parts are kept and billed until the bucket lifecycle removes them
+            if let Err(err) = client
+                .abort_multipart_upload()
+                .bucket(bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .send()
+                .await
+            {
+                warn!(?err, upload_id, "fail to abort multipart upload");
+            }
+            return Err(err);
+        }
+    };
+
+    debug!(parts = parts.len(), key, "complete multipart upload");
+    let upload = CompletedMultipartUpload::builder()
+        .set_parts(Some(parts))
+        .build();
+    retry(&config.retry, || async {
         client
-            .put_object()
+            .complete_multipart_upload()
             .bucket(bucket)
             .key(key)
-            .content_type(content_type)
-            .content_md5(content_md5)
-            .body(stream)
+            .upload_id(upload_id)
+            .multipart_upload(upload.clone())
             .send()
             .await
-            .map_err(
-                |err| Error::from(Box::new(err) as Box<dyn std::error::Error + Send + Sync>),
-            )?;
+            .map_err(aws_error)?;
         Ok(())
     })
     .await
 }
 
-#[instrument(err, skip(path))]
-async fn calculate_md5(path: &Path) -> Result<String, Error> {
-    let mut file = File::open(path).await.map_err(Error::from)?;
-    let mut hasher = Md5::new();
-    let mut buffer = [0; 1024];
+async fn put_parts(
+    client: &Client,
+    config: &Config,
+    bucket: &str,
+    full_path: &Path,
+    key: &str,
+    upload_id: &str,
+) -> Result<Vec<CompletedPart>, AggregateError<Box<Error>>> {
+    let mut file = File::open(full_path).await.map_err(aggregate)?;
+    let mut parts = Vec::new();
+
+    for part_number in 1.. {
+        let body = read_part(&mut file, config.upload.part_size)
+            .await
+            .map_err(aggregate)?;
+        if body.is_empty() {
+            break;
+        }
+        let content_md5 = content_md5(&body);
+
+        let e_tag = retry(&config.retry, || async {
+            let output = client
+                .upload_part()
+                .bucket(bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .content_md5(&content_md5)
+                .body(ByteStream::from(body.clone()))
+                .send()
+                .await
+                .map_err(aws_error)?;
+            Ok::<_, Error>(output.e_tag().map(ToOwned::to_owned))
+        })
+        .await?;
+
+        parts.push(
+            CompletedPart::builder()
+                .part_number(part_number)
+                .set_e_tag(e_tag)
+                .build(),
+        );
+    }
 
+    Ok(parts)
+}
+
+/// Read up to `part_size` bytes, less only at the end of the file
+async fn read_part(reader: &mut (impl AsyncRead + Unpin), part_size: u64) -> Result<Bytes, Error> {
+    let mut buffer = Vec::with_capacity(part_size as usize);
+    reader.take(part_size).read_to_end(&mut buffer).await?;
+    Ok(Bytes::from(buffer))
+}
+
+fn content_type(path: &Path) -> String {
+    mime_guess::from_path(path).first().map_or_else(
+        || "application/octet-stream".to_owned(),
+        |mime| mime.to_string(),
+    )
+}
+
+/// Encodings of the compressed variants uploaded next to the file, none for multipart uploads
+fn encodings<'a>(config: &'a Config, content_type: &str, size: u64) -> &'a [Encoding] {
+    if size >= config.upload.multipart_threshold {
+        &[]
+    } else {
+        config.upload.headers.encodings(content_type, size)
+    }
+}
+
+/// ETag S3 computes for a multipart upload, the MD5 of the part MD5s followed by the part count
+async fn multipart_e_tag(path: &Path, part_size: u64) -> Result<String, Error> {
+    let mut file = File::open(path).await?;
+    let mut digests = Vec::new();
+    let mut parts = 0;
     loop {
-        let bytes_read = file.read(&mut buffer).await.map_err(Error::from)?;
-        if bytes_read == 0 {
+        let part = read_part(&mut file, part_size).await?;
+        if part.is_empty() {
             break;
         }
+        digests.extend_from_slice(&Md5::digest(&part));
+        parts += 1;
+    }
+    Ok(format!("{}-{parts}", hex(&Md5::digest(&digests))))
+}
+
+fn content_md5(body: &[u8]) -> String {
+    STANDARD.encode(Md5::digest(body))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn aws_error<E>(err: SdkError<E, HttpResponse>) -> Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    Error::Aws {
+        retryable: is_retryable_sdk(&err),
+        source: Box::new(err),
+    }
+}
+
+fn aggregate(err: impl Into<Error>) -> AggregateError<Box<Error>> {
+    AggregateError::from(vec![Box::new(err.into())])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_config::{retry::RetryConfig, BehaviorVersion};
+    use aws_credential_types::Credentials;
+    use aws_types::region::Region;
+    use wiremock::{
+        matchers::{body_string, header, headers, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn test_client(server: &MockServer) -> Client {
+        Client::from_conf(
+            aws_sdk_s3::Config::builder()
+                .behavior_version(BehaviorVersion::latest())
+                .endpoint_url(server.uri())
+                .region(Region::new("auto"))
+                .credentials_provider(Credentials::for_tests())
+                .retry_config(RetryConfig::disabled())
+                .force_path_style(true)
+                .build(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_upload_failure_threshold() {
+        // nothing is mounted, so every request fails right away with a 404
+        let server = MockServer::start().await;
+        let client = test_client(&server);
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.js"), "a").unwrap();
+        fs::write(dir.path().join("b.js"), "b").unwrap();
+
+        let mut config = Config::default();
+        config.retry.limit = 0;
+
+        let err = put_directory(&client, &config, "bucket", "P1/_nuxt", dir.path())
+            .await
+            .unwrap_err();
+        let Error::Upload { mut failed, .. } = err else {
+            panic!("expect upload error, got {err:?}");
+        };
+        failed.sort();
+        assert_eq!(failed, ["P1/_nuxt/a.js", "P1/_nuxt/b.js"]);
+
+        config.upload.max_failures = 2;
+        let report = put_directory(&client, &config, "bucket", "P1/_nuxt", dir.path())
+            .await
+            .unwrap();
+        assert_eq!(report.failed.len(), 2);
+        assert_eq!(
+            report.summary,
+            UploadSummary {
+                uploaded: 0,
+                skipped: 0,
+                failed: 2,
+                ..Default::default()
+            }
+        );
+
+        // a site without `_nuxt` has nothing to upload
+        let report = put_directory(
+            &client,
+            &config,
+            "bucket",
+            "P1/_nuxt",
+            dir.path().join("_nuxt"),
+        )
+        .await
+        .unwrap();
+        assert!(report.keys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_upload_attempts() {
+        let server = MockServer::start().await;
+        let client = test_client(&server);
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.js"), "a").unwrap();
+
+        // one attempt and one retry, no second round on top
+        Mock::given(method("PUT"))
+            .and(path("/bucket/P1/_nuxt/a.js"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let mut config = Config::default();
+        config.retry.limit = 1;
+        config.retry.delay_secs = 0;
+        config.upload.max_failures = 1;
+        let report = put_directory(&client, &config, "bucket", "P1/_nuxt", dir.path())
+            .await
+            .unwrap();
+        assert_eq!(report.failed, ["P1/_nuxt/a.js"]);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_cache_control() {
+        let server = MockServer::start().await;
+        let client = test_client(&server);
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.js"), "a").unwrap();
+
+        // unchanged content, uploaded before the header policy changed
+        let listing = format!(
+            "<ListBucketResult><Name>bucket</Name><IsTruncated>false</IsTruncated>\
+             <Contents><Key>P1/_nuxt/a.js</Key><ETag>&quot;{}&quot;</ETag><Size>1</Size>\
+             <LastModified>2024-01-01T00:00:00.000Z</LastModified></Contents>\
+             </ListBucketResult>",
+            hex(&Md5::digest(b"a"))
+        );
+        let list = || {
+            Mock::given(method("GET"))
+                .and(path("/bucket/"))
+                .and(query_param("list-type", "2"))
+                .respond_with(ResponseTemplate::new(200).set_body_string(listing.as_str()))
+        };
+        list().mount(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/bucket/P1/_nuxt.header-policy"))
+            .respond_with(
+                ResponseTemplate::new(404).set_body_string(
+                    "<Error><Code>NoSuchKey</Code><Message>missing</Message></Error>",
+                ),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("HEAD"))
+            .and(path("/bucket/P1/_nuxt/a.js"))
+            .respond_with(ResponseTemplate::new(200).insert_header("cache-control", "no-cache"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path("/bucket/P1/_nuxt/a.js"))
+            .and(header("x-amz-copy-source", "bucket/P1/_nuxt/a.js"))
+            .and(header("x-amz-metadata-directive", "REPLACE"))
+            .and(headers("cache-control", vec!["public", "max-age=60"]))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string("<CopyObjectResult><ETag>\"e\"</ETag></CopyObjectResult>"),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        let config = Config::default();
+        let policy = config.upload.headers.fingerprint();
+        Mock::given(method("PUT"))
+            .and(path("/bucket/P1/_nuxt.header-policy"))
+            .and(body_string(policy.as_str()))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let report = put_directory(&client, &config, "bucket", "P1/_nuxt", dir.path())
+            .await
+            .unwrap();
+        assert_eq!(report.summary.skipped, 1);
+        server.verify().await;
+
+        // the recorded policy is the current one, the headers are left alone
+        server.reset().await;
+        list().mount(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/bucket/P1/_nuxt.header-policy"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(policy.as_str()))
+            .mount(&server)
+            .await;
+        Mock::given(method("HEAD"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let report = put_directory(&client, &config, "bucket", "P1/_nuxt", dir.path())
+            .await
+            .unwrap();
+        assert_eq!(report.summary.skipped, 1);
+    }
+
+    #[tokio::test]
+    async fn test_read_part() {
+        let data = (0..25u8).collect::<Vec<_>>();
+        let mut reader = &data[..];
+
+        let mut parts = Vec::new();
+        loop {
+            let part = read_part(&mut reader, 10).await.unwrap();
+            if part.is_empty() {
+                break;
+            }
+            parts.push(part);
+        }
 
-        hasher.update(&buffer[..bytes_read]);
+        assert_eq!(
+            parts.iter().map(|part| part.len()).collect::<Vec<_>>(),
+            [10, 10, 5]
+        );
+        assert_eq!(parts.concat(), data);
+        assert_eq!(content_md5(b""), "1B2M2Y8AsgTpgAmY7PhCfg==");
     }
 
-    let content_md5 = STANDARD.encode(&hasher.finalize());
-    Ok(content_md5)
+    #[tokio::test]
+    async fn test_multipart_e_tag() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, b"hello world").unwrap();
+
+        // single part is still a multipart ETag with the `-1` suffix
+        assert_eq!(
+            multipart_e_tag(file.path(), 1024).await.unwrap(),
+            format!("{}-1", hex(&Md5::digest(Md5::digest(b"hello world"))))
+        );
+
+        let digests = [
+            Md5::digest(b"hello"),
+            Md5::digest(b" worl"),
+            Md5::digest(b"d"),
+        ]
+        .concat();
+        assert_eq!(
+            multipart_e_tag(file.path(), 5).await.unwrap(),
+            format!("{}-3", hex(&Md5::digest(digests)))
+        );
+        assert_eq!(hex(&Md5::digest(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+    }
 }
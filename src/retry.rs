@@ -1,46 +1,208 @@
-use std::{future::Future, time::Duration};
+use aws_sdk_s3::{config::http::HttpResponse, error::SdkError};
+use backon::{BackoffBuilder, ExponentialBackoff, ExponentialBuilder};
+use std::{
+    future::Future,
+    io,
+    time::{Duration, Instant},
+};
 use tokio::time;
-use tracing::warn;
+use tracing::{info, warn};
 
-use crate::errors::AggregateError;
+use crate::{config::RetryConfig, errors::AggregateError};
 
-const RETRY_LIMIT: i32 = 1;
-const DELAY_SECS_BETWEEN_RETRY: u64 = 2;
+/// Errors which may go away when the operation is tried again
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+}
+
+/// Exponential backoff with optional jitter, bounded by the number of attempts and the time
+/// since the first one
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt
+    max_retries: u32,
+    initial_delay: Duration,
+    max_delay: Duration,
+    factor: f32,
+    jitter: bool,
+    /// No retry starts after this, whatever the attempts left
+    max_elapsed: Duration,
+}
+
+impl From<&RetryConfig> for RetryPolicy {
+    fn from(config: &RetryConfig) -> Self {
+        Self {
+            max_retries: config.limit,
+            initial_delay: Duration::from_secs(config.delay_secs),
+            max_delay: Duration::from_secs(config.max_delay_secs),
+            factor: config.factor,
+            jitter: config.jitter,
+            max_elapsed: Duration::from_secs(config.max_elapsed_secs),
+        }
+    }
+}
 
-pub async fn retry<Func, Return, ErrType>(f: Func) -> Result<(), AggregateError<Box<ErrType>>>
+impl RetryPolicy {
+    /// Delays before each retry
+    fn backoff(&self) -> ExponentialBackoff {
+        let builder = ExponentialBuilder::default()
+            .with_min_delay(self.initial_delay)
+            .with_max_delay(self.max_delay)
+            .with_factor(self.factor)
+            .with_max_times(self.max_retries as usize);
+        if self.jitter {
+            builder.with_jitter().build()
+        } else {
+            builder.build()
+        }
+    }
+}
+
+/// Call `f` until it succeeds, fails with an error which is not retryable, or runs out of the
+/// retry budget of `config`
+pub async fn retry<Func, Return, T, ErrType>(
+    config: &RetryConfig,
+    f: Func,
+) -> Result<T, AggregateError<Box<ErrType>>>
 where
     Func: FnMut() -> Return,
-    Return: Future<Output = Result<(), ErrType>>,
-    ErrType: std::error::Error + Send + Sync + 'static,
+    Return: Future<Output = Result<T, ErrType>>,
+    ErrType: std::error::Error + Retryable + Send + Sync + 'static,
 {
-    retry_with_limit(RETRY_LIMIT, f).await
+    retry_with_policy(&RetryPolicy::from(config), f).await
 }
 
-async fn retry_with_limit<Func, Return, ErrType>(
-    limit: i32,
+async fn retry_with_policy<Func, Return, T, ErrType>(
+    policy: &RetryPolicy,
     mut f: Func,
-) -> Result<(), AggregateError<Box<ErrType>>>
+) -> Result<T, AggregateError<Box<ErrType>>>
 where
     Func: FnMut() -> Return,
-    Return: Future<Output = Result<(), ErrType>>,
-    ErrType: std::error::Error + Send + Sync + 'static,
+    Return: Future<Output = Result<T, ErrType>>,
+    ErrType: std::error::Error + Retryable + Send + Sync + 'static,
 {
-    let mut retry = 0;
+    let start = Instant::now();
+    let mut backoff = policy.backoff();
     let mut errors = vec![];
     loop {
-        match f().await {
-            Ok(_) => return Ok(()),
-            Err(err) => {
-                warn!(?err, "Retry deploy: {}", retry);
-                sentry::capture_message("Retry deploy", sentry::Level::Warning);
-                errors.push(Box::new(err));
-                if retry < limit {
-                    retry += 1;
-                    time::sleep(Duration::from_secs(DELAY_SECS_BETWEEN_RETRY)).await;
-                } else {
-                    return Err(AggregateError::from(errors));
-                }
-            }
+        let err = match f().await {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+
+        let delay = if err.is_retryable() {
+            backoff
+                .next()
+                .filter(|delay| start.elapsed() + *delay <= policy.max_elapsed)
+        } else {
+            info!(?err, "not retryable, give up");
+            None
+        };
+        let Some(delay) = delay else {
+            errors.push(Box::new(err));
+            return Err(AggregateError::from(errors));
+        };
+
+        warn!(?err, attempt = errors.len() + 1, ?delay, "retry");
+        errors.push(Box::new(err));
+        time::sleep(delay).await;
+    }
+}
+
+/// Local files which are missing or unreadable stay so, other IO errors may be transient
+pub fn is_retryable_io(err: &io::Error) -> bool {
+    !matches!(
+        err.kind(),
+        io::ErrorKind::NotFound
+            | io::ErrorKind::PermissionDenied
+            | io::ErrorKind::InvalidInput
+            | io::ErrorKind::InvalidData
+            | io::ErrorKind::Unsupported
+    )
+}
+
+/// Requests which never got an answer, and server errors or throttling, may pass next time
+pub fn is_retryable_sdk<E>(err: &SdkError<E, HttpResponse>) -> bool {
+    match err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            true
         }
+        SdkError::ServiceError(err) => {
+            let status = err.raw().status().as_u16();
+            status >= 500 || status == 408 || status == 429
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("retryable: {0}")]
+    struct TestError(bool);
+
+    impl Retryable for TestError {
+        fn is_retryable(&self) -> bool {
+            self.0
+        }
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(3),
+            factor: 2.0,
+            jitter: false,
+            max_elapsed: Duration::from_secs(10),
+        }
+    }
+
+    async fn attempts(policy: &RetryPolicy, retryable: bool) -> (u32, usize) {
+        let attempts = AtomicU32::new(0);
+        let errors = retry_with_policy(policy, || async {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            Err::<(), _>(TestError(retryable))
+        })
+        .await
+        .unwrap_err();
+        (attempts.into_inner(), errors.into_vec().len())
+    }
+
+    #[test]
+    fn test_backoff() {
+        let delays = policy().backoff().collect::<Vec<_>>();
+        assert_eq!(
+            delays,
+            [1, 2, 3].map(Duration::from_millis),
+            "doubled and capped"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let policy = policy();
+        assert_eq!(attempts(&policy, true).await, (4, 4));
+        assert_eq!(attempts(&policy, false).await, (1, 1));
+
+        let policy = RetryPolicy {
+            max_elapsed: Duration::ZERO,
+            ..policy
+        };
+        assert_eq!(attempts(&policy, true).await, (1, 1));
+
+        let attempts = AtomicU32::new(0);
+        let value = retry_with_policy(&self::policy(), || async {
+            match attempts.fetch_add(1, Ordering::Relaxed) {
+                0 => Err(TestError(true)),
+                attempt => Ok(attempt),
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(value, 1);
     }
 }
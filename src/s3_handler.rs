@@ -1,31 +1,42 @@
 use crate::{
-    api::{get_site, update_release, Client, ReleaseState},
-    check_version::wait_version_match,
+    api::{get_site, release_done, update_release, Client, ReleaseState},
+    asset_gc,
+    claim::{self, Claim, ClaimState, CLAIM_PREFIX},
     clean_files::clean_unused_files,
+    config::Config,
+    deploy_target::{self, create_r2_client, Site},
     errors::ProcessFileError,
+    extract::{self, ExtractError, ExtractLimits},
+    integrity::{HashingReader, Integrity},
     metric,
     nuxt_variant::NuxtVariant,
-    put_directory::put_directory,
+    signature::SIGNATURE_HEADER,
     sitemap::submit_sitemap,
-    types::{DeployMeta, DeployType, FileSummary},
+    types::{DeployMeta, DeployType, FileSummary, MetaError},
     verify_site::verify_site,
-    wrangler::{self, WRANGLER_ROOT},
+    wrangler,
 };
 use aws_config::BehaviorVersion;
-use aws_credential_types::{provider::SharedCredentialsProvider, Credentials};
 use aws_lambda_events::s3::{S3Bucket, S3Entity, S3Event, S3EventRecord, S3Object};
 use aws_sdk_s3::{error::SdkError, operation::get_object::GetObjectError};
-use aws_types::region::Region;
-use once_cell::sync::Lazy;
+use futures::{stream, StreamExt};
 use percent_encoding::percent_decode;
 use scopeguard::ScopeGuard;
 use serde_derive::Serialize;
-use std::{convert::Infallible, env, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    io::{self, Cursor, Read, Seek},
+    path::Path,
+    sync::Arc,
+};
 use tap::prelude::*;
 use tempfile::tempdir_in;
-use tokio::{io::AsyncRead, runtime::Handle};
-use tokio_util::io::SyncIoBridge;
-use tracing::{debug, debug_span, error, info, info_span, instrument, warn, Instrument};
+use tokio::{fs::File, io::AsyncRead, runtime::Handle, select};
+use tokio_util::{io::SyncIoBridge, sync::CancellationToken};
+use tracing::{
+    debug, debug_span, error, field, info, info_span, instrument, warn, Instrument, Span,
+};
 
 #[derive(Debug, Serialize)]
 pub struct SuccessResponse {
@@ -37,32 +48,89 @@ pub struct SuccessResponse {
 pub struct FailureResponse {
     pub success: Vec<String>,
     pub failed: Vec<String>,
+    /// Keys claimed by another worker, should be retried later
+    pub deferred: Vec<String>,
 }
 
 pub type Response = Result<SuccessResponse, FailureResponse>;
 
-static R2_CREDENTIALS: Lazy<SharedCredentialsProvider> = Lazy::new(|| {
-    SharedCredentialsProvider::new(Credentials::new(
-        env::var("R2_ACCESS_KEY").expect("R2_ACCESS_KEY is missing"),
-        env::var("R2_SECRET_KEY").expect("R2_SECRET_KEY is missing"),
-        None,
-        None,
-        "r2",
-    ))
-});
-
-#[instrument(ret, err)]
-pub async fn handle_s3_event(payload: S3Event) -> Response {
+/// Placeholder bucket name for archives deployed from the local file system
+const LOCAL_BUCKET: &str = "local";
+/// Bytes hashed past the end of an archive of unknown size, e.g. the zero records padding a tar
+const MAX_TRAILER: u64 = 1024 * 1024;
+
+/// Handle all records of an S3 event, `shutdown` aborts the in-flight deployments
+/// and reports their releases as queued so they can be picked up again
+#[instrument(ret, err, skip(config, shutdown))]
+pub async fn handle_s3_event(
+    payload: S3Event,
+    config: Arc<Config>,
+    shutdown: CancellationToken,
+) -> Response {
     info!(?payload, "handling a request...");
 
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let s3_client = aws_sdk_s3::Client::new(&config);
-    let cw_client = aws_sdk_cloudwatch::Client::new(&config);
+    let shared_config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+    let s3_client = aws_sdk_s3::Client::new(&shared_config);
+    let cw_client = aws_sdk_cloudwatch::Client::new(&shared_config);
+
+    info!("total records: {}", payload.records.len());
+    let records = event_records(payload);
+
+    let concurrency = config.record_concurrency;
+    debug!(concurrency, "start handling records");
+
+    let results = stream::iter(records)
+        .map(|(bucket, key)| {
+            let s3_client = s3_client.clone();
+            let cw_client = cw_client.clone();
+            let config = config.clone();
+            let shutdown = shutdown.clone();
+            async move {
+                let handling_span = debug_span!("handling request", bucket, key);
+                // spawn each record so a panic only fails its own key
+                let handle = tokio::spawn(
+                    handle_record(s3_client, cw_client, config, bucket, key.clone(), shutdown)
+                        .instrument(handling_span),
+                );
+                match handle.await {
+                    Ok(res) => res,
+                    Err(err) => {
+                        error!(?err, key, "Record handler crashed");
+                        sentry::capture_error(&err);
+                        RecordResult::Failed(key)
+                    }
+                }
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+
     let mut processed = Vec::new();
     let mut failed = Vec::new();
+    let mut deferred = Vec::new();
+    for res in results {
+        match res {
+            RecordResult::Processed(key) => processed.push(key),
+            RecordResult::Failed(key) => failed.push(key),
+            RecordResult::Deferred(key) => deferred.push(key),
+        }
+    }
 
-    info!("total records: {}", payload.records.len());
-    let records = payload
+    if failed.is_empty() && deferred.is_empty() {
+        Ok(SuccessResponse { processed })
+    } else {
+        Err(FailureResponse {
+            success: processed,
+            failed,
+            deferred,
+        })
+    }
+}
+
+/// Extract `(bucket, key)` of the records, keys are still percent encoded
+fn event_records(payload: S3Event) -> Vec<(String, String)> {
+    payload
         .records
         .into_iter()
         .filter_map(|mut record| {
@@ -97,76 +165,203 @@ pub async fn handle_s3_event(payload: S3Event) -> Response {
                     return None;
                 }
             };
+            if key.starts_with(CLAIM_PREFIX) {
+                debug!(key, "skip claim object");
+                return None;
+            }
             Some((bucket, key))
         })
-        .collect::<Vec<_>>();
+        .collect()
+}
 
-    for (bucket, key) in records {
-        let handling_span = debug_span!("handling request", bucket, key);
-        let _handling_guard = handling_span.enter();
+enum RecordResult {
+    Processed(String),
+    Failed(String),
+    /// Claimed by another worker, nothing is done
+    Deferred(String),
+}
 
-        let key = match percent_decode(key.as_bytes()).decode_utf8() {
-            Ok(key) => key.into_owned(),
-            Err(err) => {
-                error!(key, "Fail to decode key");
-                sentry::capture_error(&err);
-                failed.push(key);
-                continue;
-            }
-        };
+/// Process a single record and report its key with the result
+async fn handle_record(
+    s3_client: aws_sdk_s3::Client,
+    cw_client: aws_sdk_cloudwatch::Client,
+    config: Arc<Config>,
+    bucket: String,
+    key: String,
+    shutdown: CancellationToken,
+) -> RecordResult {
+    if shutdown.is_cancelled() {
+        warn!(key, "Skip record due to shutdown");
+        return RecordResult::Failed(key);
+    }
 
-        // TODO: retry if error
-        // TODO: parallel handling if possible
-        if let Err(err) = process_file(&s3_client, &cw_client, &bucket, &key).await {
-            error!(?err, "Error when process {bucket}/{key}");
+    let key = match percent_decode(key.as_bytes()).decode_utf8() {
+        Ok(key) => key.into_owned(),
+        Err(err) => {
+            error!(key, "Fail to decode key");
             sentry::capture_error(&err);
-            failed.push(key);
-            continue;
+            return RecordResult::Failed(key);
         }
+    };
 
-        if let Err(err) = s3_client
-            .delete_object()
-            .bucket(&bucket)
-            .key(&key)
-            .send()
-            .await
-        {
-            error!(?err, "Fail to cleanup {bucket}/{key}");
+    match process_file(&s3_client, &cw_client, &config, &bucket, &key, &shutdown).await {
+        Ok(()) => RecordResult::Processed(key),
+        Err(ProcessFileError::Claimed) => {
+            info!("Defer {bucket}/{key} as it is claimed by another worker");
+            RecordResult::Deferred(key)
+        }
+        Err(ProcessFileError::Cancelled) => {
+            warn!("Cancel processing {bucket}/{key} due to shutdown");
+            RecordResult::Failed(key)
+        }
+        Err(err) => {
+            error!(?err, "Error when process {bucket}/{key}");
             sentry::capture_error(&err);
+            RecordResult::Failed(key)
         }
-
-        processed.push(key);
-    }
-
-    if failed.is_empty() {
-        Ok(SuccessResponse { processed })
-    } else {
-        Err(FailureResponse {
-            success: processed,
-            failed,
-        })
     }
 }
 
-#[instrument(err, skip(s3_client, cw_client))]
+#[instrument(err, skip(s3_client, cw_client, config, shutdown))]
 pub async fn process_file(
     s3_client: &aws_sdk_s3::Client,
     cw_client: &aws_sdk_cloudwatch::Client,
+    config: &Config,
     bucket: &str,
     key: &str,
+    shutdown: &CancellationToken,
 ) -> Result<(), ProcessFileError> {
-    wrangler::init();
+    wrangler::init(config);
     let metric_guard = metric::start(cw_client);
 
-    let Some((mut meta, body_stream)) = get_file(s3_client, bucket, key).await? else {
+    let Some(Archive {
+        meta,
+        hint,
+        e_tag,
+        body,
+    }) = get_file(s3_client, config, bucket, key).await?
+    else {
         // file already processed
         return Ok(());
     };
 
+    // the same event may be delivered more than once, only one worker can deploy a release
+    let Some(claim) = claim_archive(
+        s3_client,
+        config,
+        bucket,
+        key,
+        &meta.release_id,
+        e_tag.as_deref(),
+    )
+    .await?
+    else {
+        return Ok(());
+    };
+
+    let res = deploy(config, meta, bucket, key, hint, body, shutdown).await;
+    // remove the archive before releasing, so a redelivered event finds nothing to deploy
+    if res.is_ok() {
+        if let Err(err) = s3_client
+            .delete_object()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            error!(?err, "Fail to cleanup {bucket}/{key}");
+            sentry::capture_error(&err);
+        }
+    }
+    claim.release().await;
+    let (client, summary) = res?;
+
+    metric_guard.stop(&client.meta, &summary).await;
+
+    tokio::spawn(async move {
+        verify_site(client).await;
+    });
+    Ok(())
+}
+
+/// Claim the release of an archive, as long as the archive is still the one read by `get_file`
+///
+/// A worker which read the archive while another one was deploying it only gets the claim once
+/// that deploy deleted the archive, `None` then tells the archive was already processed.
+#[instrument(err, skip(s3_client, config))]
+async fn claim_archive<'a>(
+    s3_client: &'a aws_sdk_s3::Client,
+    config: &Config,
+    bucket: &str,
+    key: &str,
+    release_id: &str,
+    e_tag: Option<&str>,
+) -> Result<Option<Claim<'a>>, ProcessFileError> {
+    let ClaimState::Claimed(claim) =
+        claim::claim(s3_client, bucket, release_id, config.claim_ttl()).await?
+    else {
+        return Err(ProcessFileError::Claimed);
+    };
+
+    let res = s3_client
+        .head_object()
+        .bucket(bucket)
+        .key(key)
+        .set_if_match(e_tag.map(ToOwned::to_owned))
+        .send()
+        .await;
+    match res {
+        Ok(_) => Ok(Some(claim)),
+        // deleted by the deploy, or replaced by an archive which has its own event
+        Err(SdkError::ServiceError(err))
+            if err.err().is_not_found() || err.raw().status().as_u16() == 412 =>
+        {
+            info!("{bucket}/{key} processed while claiming");
+            claim.release().await;
+            Ok(None)
+        }
+        Err(err) => {
+            error!(?err, "Fail to check object {bucket}/{key}");
+            claim.release().await;
+            Err(ProcessFileError::S3Error)
+        }
+    }
+}
+
+/// Deploy an archive from the local file system, bypassing S3 and SQS
+#[instrument(err, skip(config, meta))]
+pub async fn process_local_file(
+    config: &Config,
+    meta: DeployMeta,
+    path: &Path,
+) -> Result<FileSummary, ProcessFileError> {
+    wrangler::init(config);
+
+    let file = File::open(path).await?;
+    let key = path.display().to_string();
+    let hint = ArchiveHint::default();
+    let shutdown = CancellationToken::new();
+    let (_, summary) = deploy(config, meta, LOCAL_BUCKET, &key, hint, file, &shutdown).await?;
+
+    Ok(summary)
+}
+
+/// Run the deploy pipeline and mark the release as error if it fails, or as queued if
+/// it is cancelled by `shutdown`
+async fn deploy(
+    config: &Config,
+    mut meta: DeployMeta,
+    bucket: &str,
+    key: &str,
+    hint: ArchiveHint,
+    body_stream: impl AsyncRead + Unpin + Send,
+    shutdown: &CancellationToken,
+) -> Result<(Client, FileSummary), ProcessFileError> {
     meta.derive_deploy_type_from_source();
 
     let client = Client::new(meta);
 
+    // only runs when the deploy panics, errors are reported below
     let executor = Handle::current();
     let client = scopeguard::guard(client, |client| {
         executor.spawn(async move {
@@ -184,27 +379,43 @@ pub async fn process_file(
         release_id = meta.release_id,
         source = meta.source,
         deploy_type = ?meta.deploy_type,
+        target = ?meta.target,
     );
 
-    let summary = do_process_file(&client, bucket, key, body_stream)
-        .instrument(span)
-        .await?;
+    let res = select! {
+        res = do_process_file(config, &client, bucket, key, hint, body_stream).instrument(span) => Some(res),
+        _ = shutdown.cancelled() => None,
+    };
 
+    let Some(res) = res else {
+        let client = ScopeGuard::into_inner(client);
+        update_release(&client, ReleaseState::Queued).await;
+        return Err(ProcessFileError::Cancelled);
+    };
     let client = ScopeGuard::into_inner(client);
+    let summary = match res {
+        Ok(summary) => summary,
+        Err(err) => {
+            // awaited here, a task spawned from the guard is lost when manual_deploy exits
+            update_release(&client, ReleaseState::Error).await;
+            return Err(err);
+        }
+    };
 
-    metric_guard.stop(&client.meta, &summary).await;
-
-    tokio::spawn(async move {
-        verify_site(client).await;
-    });
-    Ok(())
+    Ok((client, summary))
 }
 
-#[instrument(err, skip(body_stream))]
+#[instrument(
+    err,
+    skip(config, body_stream),
+    fields(deployment_id = field::Empty, deployment_url = field::Empty)
+)]
 async fn do_process_file(
+    config: &Config,
     api_client: &Client,
     bucket: &str,
     key: &str,
+    hint: ArchiveHint,
     body_stream: impl AsyncRead + Unpin + Send,
 ) -> Result<FileSummary, ProcessFileError> {
     let meta = &api_client.meta;
@@ -221,11 +432,12 @@ async fn do_process_file(
     }
 
     update_release(api_client, ReleaseState::Uploading).await;
-    let dir = tempdir_in(WRANGLER_ROOT)?;
+    let dir = tempdir_in(&config.wrangler.root)?;
     let tmp_path = dir.path();
     info!("extract to {}", tmp_path.display());
-    extract_to(body_stream, tmp_path).await?;
-    let summary = clean_unused_files(tmp_path);
+    let integrity = Integrity::from_meta(meta);
+    extract_to(body_stream, key, hint, integrity, config.extract, tmp_path).await?;
+    let mut summary = clean_unused_files(tmp_path);
 
     let (site_root, deploy_path) = match (meta.deploy_type, meta.output_path.as_deref()) {
         (DeployType::CloudflareFunction, None) => (
@@ -238,29 +450,21 @@ async fn do_process_file(
 
     debug!(site_root = %site_root.display(), deploy_path = %deploy_path.display(), "detect root");
 
-    if !meta.is_static() {
-        info!("put to r2");
-        let r2_client = create_r2_client();
-        let local_path = tmp_path.join(deploy_path).join("_nuxt");
-
-        put_directory(
-            &r2_client,
-            "storipress",
-            &format!("{}/_nuxt", api_client.meta.client_id),
-            &local_path,
-        )
-        .await?;
-
-        info!("r2 put success");
-    } else {
-        info!("skip put to r2");
+    let site = Site::new(meta, site_root, deploy_path);
+    let (upload, deployment) = deploy_target::publish(config, &site).await?;
+    summary.upload = upload;
+    if let Some(deployment) = &deployment {
+        // events reported later in the deploy tell which deployment they are about
+        let span = Span::current();
+        span.record("deployment_id", deployment.deployment_id.as_deref());
+        span.record("deployment_url", deployment.url.as_deref());
+        info!(?deployment, "Pages deployment created");
     }
 
-    debug!(?site_root, ?deploy_path, "detect root");
-
-    wrangler::spawn(&meta, site_root, deploy_path).await?;
-
-    update_release(api_client, ReleaseState::Done).await;
+    // the API rejects the whole update when it doesn't know the deployment fields
+    let reported = deployment.as_ref().filter(|_| config.report_deployment);
+    release_done(api_client, reported).await;
+    summary.deployment = deployment;
 
     info!("Deploy success success");
 
@@ -268,6 +472,18 @@ async fn do_process_file(
         error!(?err, "Fail to submit sitemap");
     }
 
+    // without the manifest of this release, its assets would look stale
+    if config.gc.enabled && summary.upload.manifest_written {
+        let r2_client = create_r2_client(&config.r2);
+        match asset_gc::collect(&r2_client, config, &meta.client_id).await {
+            Ok(gc) => info!(?gc, "asset gc finished"),
+            Err(err) => {
+                error!(?err, "Fail to collect stale assets");
+                sentry::capture_error(&err);
+            }
+        }
+    }
+
     Ok(summary)
 }
 
@@ -284,14 +500,48 @@ async fn do_submit_sitemap(client: &Client) -> anyhow::Result<()> {
 #[instrument(err, skip(body_stream))]
 async fn extract_to(
     body_stream: impl AsyncRead + Unpin + Send,
+    key: &str,
+    hint: ArchiveHint,
+    integrity: Integrity,
+    limits: ExtractLimits,
     tmp_path: &Path,
 ) -> Result<(), ProcessFileError> {
-    let archive_file = SyncIoBridge::new(body_stream);
+    let mut archive_file = HashingReader::new(SyncIoBridge::new(body_stream));
     let (res, outputs) = async_scoped::TokioScope::scope_and_block(move |s| {
         s.spawn_blocking(move || {
-            let archive_file = brotli::Decompressor::new(archive_file, 4096);
-            let mut archive_file = tar::Archive::new(archive_file);
-            archive_file.unpack(tmp_path)
+            // enough to find the `ustar` magic of a tar header
+            let mut head = Vec::with_capacity(512);
+            (&mut archive_file).take(512).read_to_end(&mut head)?;
+            let format = detect_format(key, &hint, &head);
+            info!(format = format.name(), "detect archive format");
+            let mut archive_file = Cursor::new(head).chain(archive_file);
+            let res = format.unpack(&mut archive_file, tmp_path, &limits);
+
+            let (_, archive_file) = archive_file.into_inner();
+            // one byte past the expected size is enough to tell it is too long
+            let limit = match integrity.size {
+                Some(size) => size.saturating_add(1),
+                None => archive_file.size().saturating_add(MAX_TRAILER),
+            };
+            match res {
+                Ok(()) if integrity.is_empty() => Ok(()),
+                Ok(()) => {
+                    let (size, sha256) = archive_file.finish(limit)?;
+                    integrity.verify(size, &sha256)?;
+                    debug!(size, sha256, "archive integrity verified");
+                    Ok(())
+                }
+                // a truncated or corrupted upload usually breaks the decoder first, report the
+                // mismatch instead when the expected size tells how much is left to read
+                Err(err) => match integrity.size {
+                    Some(_) => {
+                        let (size, sha256) = archive_file.finish(limit)?;
+                        integrity.verify(size, &sha256)?;
+                        Err(err)
+                    }
+                    None => Err(err),
+                },
+            }
         });
 
         Ok::<_, Infallible>(())
@@ -301,18 +551,237 @@ async fn extract_to(
 
     match outputs.into_iter().next() {
         Some(Ok(Ok(()))) => Ok(()),
-        Some(Ok(Err(err))) => Err(ProcessFileError::Io(err)),
+        Some(Ok(Err(err))) => Err(err),
         Some(Err(err)) => Err(ProcessFileError::JoinError(err)),
         None => unreachable!("must have a least one item"),
     }
 }
 
-#[instrument(err, skip(s3_client))]
+/// Headers of the archive object which may tell its format
+#[derive(Debug, Default)]
+pub struct ArchiveHint {
+    pub content_type: Option<String>,
+    pub content_encoding: Option<String>,
+}
+
+/// An archive format the generator may upload, add new formats to [`ARCHIVE_FORMATS`]
+trait ArchiveFormat: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Key suffixes, e.g. `.tar.gz`
+    fn extensions(&self) -> &[&str];
+
+    /// Values of `Content-Type` or `Content-Encoding` identifying the format
+    fn media_types(&self) -> &[&str];
+
+    /// Whether the first bytes of the archive belong to this format
+    fn magic(&self, head: &[u8]) -> bool;
+
+    fn unpack(
+        &self,
+        archive: &mut dyn Read,
+        dst: &Path,
+        limits: &ExtractLimits,
+    ) -> Result<(), ProcessFileError>;
+}
+
+struct TarBrotli;
+
+impl ArchiveFormat for TarBrotli {
+    fn name(&self) -> &'static str {
+        "tar.br"
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &[".tar.br"]
+    }
+
+    fn media_types(&self) -> &[&str] {
+        &["br"]
+    }
+
+    fn magic(&self, _head: &[u8]) -> bool {
+        // brotli streams have no magic bytes
+        false
+    }
+
+    fn unpack(
+        &self,
+        archive: &mut dyn Read,
+        dst: &Path,
+        limits: &ExtractLimits,
+    ) -> Result<(), ProcessFileError> {
+        let archive = brotli::Decompressor::new(archive, 4096);
+        extract::unpack(&mut tar::Archive::new(archive), dst, limits)
+    }
+}
+
+struct TarGzip;
+
+impl ArchiveFormat for TarGzip {
+    fn name(&self) -> &'static str {
+        "tar.gz"
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &[".tar.gz", ".tgz"]
+    }
+
+    fn media_types(&self) -> &[&str] {
+        &["gzip", "application/gzip", "application/x-gzip"]
+    }
+
+    fn magic(&self, head: &[u8]) -> bool {
+        head.starts_with(&[0x1f, 0x8b])
+    }
+
+    fn unpack(
+        &self,
+        archive: &mut dyn Read,
+        dst: &Path,
+        limits: &ExtractLimits,
+    ) -> Result<(), ProcessFileError> {
+        let archive = flate2::read::GzDecoder::new(archive);
+        extract::unpack(&mut tar::Archive::new(archive), dst, limits)
+    }
+}
+
+struct TarZstd;
+
+impl ArchiveFormat for TarZstd {
+    fn name(&self) -> &'static str {
+        "tar.zst"
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &[".tar.zst", ".tzst"]
+    }
+
+    fn media_types(&self) -> &[&str] {
+        &["zstd", "application/zstd"]
+    }
+
+    fn magic(&self, head: &[u8]) -> bool {
+        head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd])
+    }
+
+    fn unpack(
+        &self,
+        archive: &mut dyn Read,
+        dst: &Path,
+        limits: &ExtractLimits,
+    ) -> Result<(), ProcessFileError> {
+        let archive = zstd::Decoder::new(archive)?;
+        extract::unpack(&mut tar::Archive::new(archive), dst, limits)
+    }
+}
+
+struct Tar;
+
+impl ArchiveFormat for Tar {
+    fn name(&self) -> &'static str {
+        "tar"
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &[".tar"]
+    }
+
+    fn media_types(&self) -> &[&str] {
+        &["application/x-tar"]
+    }
+
+    fn magic(&self, head: &[u8]) -> bool {
+        head.get(257..262) == Some(b"ustar")
+    }
+
+    fn unpack(
+        &self,
+        archive: &mut dyn Read,
+        dst: &Path,
+        limits: &ExtractLimits,
+    ) -> Result<(), ProcessFileError> {
+        extract::unpack(&mut tar::Archive::new(archive), dst, limits)
+    }
+}
+
+struct Zip;
+
+impl ArchiveFormat for Zip {
+    fn name(&self) -> &'static str {
+        "zip"
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &[".zip"]
+    }
+
+    fn media_types(&self) -> &[&str] {
+        &["application/zip", "application/x-zip-compressed"]
+    }
+
+    fn magic(&self, head: &[u8]) -> bool {
+        head.starts_with(b"PK\x03\x04")
+    }
+
+    fn unpack(
+        &self,
+        archive: &mut dyn Read,
+        dst: &Path,
+        limits: &ExtractLimits,
+    ) -> Result<(), ProcessFileError> {
+        // the central directory is at the end, spool the stream to an unnamed file to seek it
+        let mut file = tempfile::tempfile_in(dst)?;
+        if io::copy(&mut archive.take(limits.max_bytes + 1), &mut file)? > limits.max_bytes {
+            return Err(ExtractError::TooLarge {
+                limit: limits.max_bytes,
+            }
+            .into());
+        }
+        file.rewind()?;
+        extract::unpack_zip(file, dst, limits)
+    }
+}
+
+static ARCHIVE_FORMATS: &[&dyn ArchiveFormat] = &[&TarBrotli, &TarGzip, &TarZstd, &Tar, &Zip];
+
+/// Detect the format by key extension, then object headers, then magic bytes,
+/// falls back to `.tar.br` which is the format of the older generators
+fn detect_format(key: &str, hint: &ArchiveHint, head: &[u8]) -> &'static dyn ArchiveFormat {
+    let media_types = [&hint.content_encoding, &hint.content_type];
+    let media_types = media_types.iter().filter_map(|value| value.as_deref());
+
+    ARCHIVE_FORMATS
+        .iter()
+        .find(|format| format.extensions().iter().any(|ext| key.ends_with(ext)))
+        .or_else(|| {
+            media_types.into_iter().find_map(|media_type| {
+                ARCHIVE_FORMATS
+                    .iter()
+                    .find(|format| format.media_types().contains(&media_type))
+            })
+        })
+        .or_else(|| ARCHIVE_FORMATS.iter().find(|format| format.magic(head)))
+        .copied()
+        .unwrap_or(&TarBrotli)
+}
+
+/// Archive object read by [`get_file`]
+struct Archive<R> {
+    meta: DeployMeta,
+    hint: ArchiveHint,
+    /// Tells whether the object changed since it was read
+    e_tag: Option<String>,
+    body: R,
+}
+
+#[instrument(err, skip(s3_client, config))]
 async fn get_file(
     s3_client: &aws_sdk_s3::Client,
+    config: &Config,
     bucket: &str,
     key: &str,
-) -> Result<Option<(DeployMeta, impl AsyncRead)>, ProcessFileError> {
+) -> Result<Option<Archive<impl AsyncRead>>, ProcessFileError> {
     let object = match s3_client.get_object().bucket(bucket).key(key).send().await {
         Ok(object) => object,
         Err(err) => match err {
@@ -326,29 +795,287 @@ async fn get_file(
             }
         },
     };
-    let meta = object
-        .metadata()
-        .ok_or(ProcessFileError::EmptyMeta)
-        .and_then(|meta_map| {
-            info!(?meta_map, "meta list");
-            meta_map.get("sp-deploy").ok_or(ProcessFileError::NoMeta)
-        })
-        .and_then(|value| {
-            serde_json::from_str(value).map_err(|source| ProcessFileError::InvalidMeta {
-                source,
-                meta: value.clone(),
-            })
-        })?;
-    Ok(Some((meta, object.body.into_async_read())))
+    let meta = parse_meta(config, object.metadata())?;
+    let hint = ArchiveHint {
+        content_type: object.content_type,
+        content_encoding: object.content_encoding,
+    };
+    Ok(Some(Archive {
+        meta,
+        hint,
+        e_tag: object.e_tag,
+        body: object.body.into_async_read(),
+    }))
+}
+
+fn parse_meta(
+    config: &Config,
+    metadata: Option<&HashMap<String, String>>,
+) -> Result<DeployMeta, ProcessFileError> {
+    let meta_map = metadata.ok_or(ProcessFileError::EmptyMeta)?;
+    info!(?meta_map, "meta list");
+    let value = meta_map.get("sp-deploy").ok_or(ProcessFileError::NoMeta)?;
+
+    // the meta picks the project and token, so it must come from a trusted generator
+    let keys = &config.deploy_meta_keys;
+    let signed = !keys.is_empty() || !config.allow_unsigned_meta;
+    if signed {
+        let signature = meta_map.get(SIGNATURE_HEADER).map(String::as_str);
+        keys.verify(value, signature)?;
+    }
+
+    let invalid = |errors| ProcessFileError::InvalidMeta {
+        meta: value.clone(),
+        errors,
+    };
+    let meta = DeployMeta::parse(value, &config.environments).map_err(invalid)?;
+
+    // the signature only covers the archive through its size and digest
+    if signed {
+        let missing = [
+            ("size", meta.size.is_none()),
+            ("sha256", meta.sha256.is_none()),
+        ]
+        .into_iter()
+        .filter(|(_, missing)| *missing)
+        .map(|(name, _)| MetaError::Missing(name))
+        .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(invalid(missing));
+        }
+    }
+    Ok(meta)
+}
+
+/// Give up on an event which keeps failing, mark the releases of its remaining archives as
+/// error and report them to Sentry. The archives are kept so they can be deployed by hand.
+#[instrument(skip(payload, config))]
+pub async fn abandon_s3_event(payload: S3Event, config: &Config, reason: &str) {
+    let shared_config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+    let s3_client = aws_sdk_s3::Client::new(&shared_config);
+
+    for (bucket, key) in event_records(payload) {
+        let key = match percent_decode(key.as_bytes()).decode_utf8() {
+            Ok(key) => key.into_owned(),
+            Err(_) => key,
+        };
+
+        let object = match s3_client
+            .head_object()
+            .bucket(&bucket)
+            .key(&key)
+            .send()
+            .await
+        {
+            Ok(object) => object,
+            Err(SdkError::ServiceError(err)) if err.err().is_not_found() => {
+                debug!("{bucket}/{key} already processed");
+                continue;
+            }
+            Err(err) => {
+                error!(?err, "Fail to get object {bucket}/{key}");
+                report_abandoned(&bucket, &key, None, reason);
+                continue;
+            }
+        };
+
+        match parse_meta(config, object.metadata()) {
+            Ok(meta) => {
+                report_abandoned(&bucket, &key, Some(&meta), reason);
+                update_release(&Client::new(meta), ReleaseState::Error).await;
+            }
+            Err(err) => {
+                error!(?err, "Fail to parse meta of {bucket}/{key}");
+                report_abandoned(&bucket, &key, None, reason);
+            }
+        }
+    }
 }
 
-fn create_r2_client() -> aws_sdk_s3::Client {
-    let r2_client_config = aws_sdk_s3::Config::builder()
-        .endpoint_url("")
-        .behavior_version(BehaviorVersion::latest())
-        .credentials_provider(R2_CREDENTIALS.clone())
-        .region(Region::new("auto"))
-        .build();
-    let r2_client = aws_sdk_s3::Client::from_conf(r2_client_config);
-    r2_client
+fn report_abandoned(bucket: &str, key: &str, meta: Option<&DeployMeta>, reason: &str) {
+    warn!(bucket, key, ?meta, reason, "abandon deploy");
+    sentry::with_scope(
+        |scope| {
+            let mut context = BTreeMap::new();
+            context.insert("bucket".to_owned(), bucket.into());
+            context.insert("key".to_owned(), key.into());
+            if let Some(meta) = meta {
+                context.insert("page_id".to_owned(), meta.page_id.as_str().into());
+                context.insert("client_id".to_owned(), meta.client_id.as_str().into());
+                context.insert("release_id".to_owned(), meta.release_id.as_str().into());
+                context.insert("source".to_owned(), meta.source.clone().into());
+                context.insert("output_path".to_owned(), meta.output_path.clone().into());
+                context.insert("deploy_type".to_owned(), meta.deploy_type.as_ref().into());
+
+                scope.set_user(Some(sentry::User {
+                    id: Some(meta.client_id.clone()),
+                    ..Default::default()
+                }));
+            }
+            scope.set_context("deploy_meta", sentry::protocol::Context::Other(context));
+        },
+        || {
+            sentry::capture_message(&format!("Abandon deploy: {reason}"), sentry::Level::Error);
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    fn tar() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, "index.html", &b"hello"[..])
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn zip() -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        writer
+            .start_file("index.html", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        writer.write_all(b"hello").unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_detect_format() {
+        let hint = ArchiveHint::default();
+        assert_eq!(detect_format("P1/a.tar.br", &hint, &[]).name(), "tar.br");
+        assert_eq!(detect_format("P1/a.tgz", &hint, &[]).name(), "tar.gz");
+        assert_eq!(detect_format("P1/a.tar.zst", &hint, &[]).name(), "tar.zst");
+        assert_eq!(detect_format("P1/a.zip", &hint, &[]).name(), "zip");
+
+        let hint = ArchiveHint {
+            content_encoding: Some("zstd".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(detect_format("P1/a", &hint, &[]).name(), "tar.zst");
+
+        let hint = ArchiveHint::default();
+        assert_eq!(detect_format("P1/a", &hint, &tar()).name(), "tar");
+        assert_eq!(detect_format("P1/a", &hint, &zip()).name(), "zip");
+        assert_eq!(
+            detect_format("P1/a", &hint, &[0x1f, 0x8b, 8]).name(),
+            "tar.gz"
+        );
+        assert_eq!(detect_format("P1/a", &hint, &[]).name(), "tar.br");
+    }
+
+    #[test]
+    fn test_unpack_formats() {
+        let tar = tar();
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        gzip.write_all(&tar).unwrap();
+        let archives: [(&dyn ArchiveFormat, Vec<u8>); 4] = [
+            (&Tar, tar.clone()),
+            (&TarGzip, gzip.finish().unwrap()),
+            (&TarZstd, zstd::encode_all(&tar[..], 0).unwrap()),
+            (&Zip, zip()),
+        ];
+
+        for (format, archive) in archives {
+            let dir = tempdir().unwrap();
+            format
+                .unpack(&mut &archive[..], dir.path(), &ExtractLimits::default())
+                .unwrap();
+            assert_eq!(
+                std::fs::read_to_string(dir.path().join("index.html")).unwrap(),
+                "hello",
+                "{} archive",
+                format.name()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_claim_processed_archive() {
+        use aws_config::retry::RetryConfig;
+        use aws_credential_types::Credentials;
+        use aws_types::region::Region;
+        use wiremock::{
+            matchers::{header, method, path, query_param},
+            Mock, MockServer, ResponseTemplate,
+        };
+
+        let server = MockServer::start().await;
+        let client = aws_sdk_s3::Client::from_conf(
+            aws_sdk_s3::Config::builder()
+                .behavior_version(BehaviorVersion::latest())
+                .endpoint_url(server.uri())
+                .region(Region::new("auto"))
+                .credentials_provider(Credentials::for_tests())
+                .retry_config(RetryConfig::disabled())
+                .force_path_style(true)
+                .build(),
+        );
+
+        // the first worker released its claim after deploying
+        Mock::given(method("GET"))
+            .and(path("/bucket/"))
+            .and(query_param("prefix", ".deploy-claims/R1/"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                "<ListBucketResult><Name>bucket</Name><IsTruncated>false</IsTruncated>\
+                 <KeyCount>0</KeyCount></ListBucketResult>",
+            ))
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path("/bucket/.deploy-claims/R1/0000000000"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/bucket/.deploy-claims/R1/0000000000"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        // and deleted the archive the second worker had read
+        Mock::given(method("HEAD"))
+            .and(path("/bucket/P1/gone.tar.br"))
+            .and(header("if-match", "\"e1\""))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+        Mock::given(method("HEAD"))
+            .and(path("/bucket/P1/new.tar.br"))
+            .and(header("if-match", "\"e1\""))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let config = Config::default();
+        let claim = claim_archive(
+            &client,
+            &config,
+            "bucket",
+            "P1/gone.tar.br",
+            "R1",
+            Some("\"e1\""),
+        )
+        .await
+        .unwrap();
+        assert!(claim.is_none(), "processed archive is not deployed again");
+
+        let claim = claim_archive(
+            &client,
+            &config,
+            "bucket",
+            "P1/new.tar.br",
+            "R1",
+            Some("\"e1\""),
+        )
+        .await
+        .unwrap();
+        assert!(claim.is_some());
+    }
 }
@@ -0,0 +1,216 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use ed25519_dalek::{Signature, VerifyingKey};
+use hmac::{Hmac, Mac};
+use serde_derive::Deserialize;
+use sha2::Sha256;
+use std::{collections::HashMap, fmt, str::FromStr};
+
+/// S3 metadata header holding the signature of the `sp-deploy` meta, as `{key_id}:{base64}`
+pub const SIGNATURE_HEADER: &str = "sp-deploy-signature";
+
+#[derive(Debug, thiserror::Error)]
+pub enum SignatureError {
+    #[error("signature is missing")]
+    Missing,
+    #[error("signature is malformed")]
+    Malformed,
+    #[error("unknown key {0}")]
+    UnknownKey(String),
+    #[error("signature does not match key {0}")]
+    Mismatch(String),
+    #[error("no key is configured to verify the signature")]
+    NoKey,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid key {0}, expect `{{key_id}}:hmac-sha256:{{base64 secret}}` or `{{key_id}}:ed25519:{{base64 public key}}`")]
+pub struct InvalidKey(String);
+
+#[derive(Clone)]
+enum Key {
+    HmacSha256(Vec<u8>),
+    Ed25519(VerifyingKey),
+}
+
+/// Keys trusted to sign deploy meta, indexed by key id
+///
+/// A key is rotated by adding the new key, switching the generator to it, then removing the old key.
+#[derive(Clone, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub struct KeySet {
+    keys: HashMap<String, Key>,
+}
+
+// only the key ids, the secrets must not be logged
+impl fmt::Debug for KeySet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.keys.keys()).finish()
+    }
+}
+
+impl FromStr for KeySet {
+    type Err = InvalidKey;
+
+    fn from_str(keys: &str) -> Result<Self, Self::Err> {
+        Self::parse(keys)
+    }
+}
+
+impl TryFrom<String> for KeySet {
+    type Error = InvalidKey;
+
+    fn try_from(keys: String) -> Result<Self, Self::Error> {
+        Self::parse(&keys)
+    }
+}
+
+impl KeySet {
+    /// Parse a comma separated list of `{key_id}:{algorithm}:{base64 key}`
+    pub fn parse(keys: &str) -> Result<Self, InvalidKey> {
+        let keys = keys
+            .split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(|spec| {
+                let invalid = || InvalidKey(spec.split(':').next().unwrap_or_default().to_owned());
+                let mut parts = spec.splitn(3, ':');
+                let (Some(id), Some(algorithm), Some(key)) =
+                    (parts.next(), parts.next(), parts.next())
+                else {
+                    return Err(invalid());
+                };
+                let key = STANDARD.decode(key).map_err(|_| invalid())?;
+                let key = match algorithm {
+                    "hmac-sha256" => Key::HmacSha256(key),
+                    "ed25519" => {
+                        let key = key.try_into().map_err(|_| invalid())?;
+                        Key::Ed25519(VerifyingKey::from_bytes(&key).map_err(|_| invalid())?)
+                    }
+                    _ => return Err(invalid()),
+                };
+                Ok((id.to_owned(), key))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { keys })
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Verify `signature` over the raw meta, nothing passes when no key is configured
+    pub fn verify(&self, meta: &str, signature: Option<&str>) -> Result<(), SignatureError> {
+        if self.is_empty() {
+            return Err(SignatureError::NoKey);
+        }
+
+        let signature = signature.ok_or(SignatureError::Missing)?;
+        let (key_id, signature) = signature.split_once(':').ok_or(SignatureError::Malformed)?;
+        let signature = STANDARD
+            .decode(signature)
+            .map_err(|_| SignatureError::Malformed)?;
+        let key = self
+            .keys
+            .get(key_id)
+            .ok_or_else(|| SignatureError::UnknownKey(key_id.to_owned()))?;
+
+        let verified = match key {
+            Key::HmacSha256(secret) => {
+                let mut mac =
+                    Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+                mac.update(meta.as_bytes());
+                mac.verify_slice(&signature).is_ok()
+            }
+            Key::Ed25519(key) => {
+                let signature =
+                    Signature::from_slice(&signature).map_err(|_| SignatureError::Malformed)?;
+                key.verify_strict(meta.as_bytes(), &signature).is_ok()
+            }
+        };
+
+        if verified {
+            Ok(())
+        } else {
+            Err(SignatureError::Mismatch(key_id.to_owned()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    const META: &str = r#"{"page_id":"p","client_id":"P1","release_id":"1"}"#;
+
+    fn hmac_sign(secret: &[u8], meta: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        mac.update(meta.as_bytes());
+        STANDARD.encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn test_verify_hmac_with_rotation() {
+        let key_set = KeySet::parse(&format!(
+            "old:hmac-sha256:{},new:hmac-sha256:{}",
+            STANDARD.encode(b"old secret"),
+            STANDARD.encode(b"new secret"),
+        ))
+        .unwrap();
+
+        for (id, secret) in [("old", &b"old secret"[..]), ("new", &b"new secret"[..])] {
+            let signature = format!("{id}:{}", hmac_sign(secret, META));
+            key_set.verify(META, Some(&signature)).unwrap();
+        }
+
+        let signature = format!("new:{}", hmac_sign(b"old secret", META));
+        assert!(matches!(
+            key_set.verify(META, Some(&signature)),
+            Err(SignatureError::Mismatch(id)) if id == "new"
+        ));
+        let signature = format!("gone:{}", hmac_sign(b"old secret", META));
+        assert!(matches!(
+            key_set.verify(META, Some(&signature)),
+            Err(SignatureError::UnknownKey(_))
+        ));
+        assert!(matches!(
+            key_set.verify(META, None),
+            Err(SignatureError::Missing)
+        ));
+    }
+
+    #[test]
+    fn test_verify_ed25519() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let key_set = KeySet::parse(&format!(
+            "k1:ed25519:{}",
+            STANDARD.encode(signing_key.verifying_key().as_bytes())
+        ))
+        .unwrap();
+
+        let signature = STANDARD.encode(signing_key.sign(META.as_bytes()).to_bytes());
+        key_set
+            .verify(META, Some(&format!("k1:{signature}")))
+            .unwrap();
+
+        let forged = META.replace("P1", "P2");
+        assert!(matches!(
+            key_set.verify(&forged, Some(&format!("k1:{signature}"))),
+            Err(SignatureError::Mismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_invalid_key() {
+        assert!(KeySet::parse("").unwrap().is_empty());
+        assert!(matches!(
+            KeySet::default().verify(META, None),
+            Err(SignatureError::NoKey)
+        ));
+        assert!(KeySet::parse("k1:rsa:AAAA").is_err());
+        assert!(KeySet::parse("k1:ed25519:AAAA").is_err());
+        assert!(KeySet::parse("k1").is_err());
+    }
+}
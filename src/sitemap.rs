@@ -9,7 +9,7 @@ pub async fn submit_sitemap(url: &str) -> reqwest_middleware::Result<()> {
             utf8_percent_encode(
                 // Must use the xml path
                 &format!("https://{url}/sitemap-index.xml"),
-                &NON_ALPHANUMERIC
+                NON_ALPHANUMERIC
             )
         ))
         .send()
@@ -0,0 +1,116 @@
+use aws_credential_types::provider::error::CredentialsError;
+use aws_sdk_sqs::{error::ProvideErrorMetadata, Error};
+use std::error::Error as StdError;
+use strum::AsRefStr;
+
+/// Error codes which will never succeed by retrying
+static FATAL_CODES: &[&str] = &[
+    "AccessDenied",
+    "AccessDeniedException",
+    "InvalidClientTokenId",
+    "MissingAuthenticationToken",
+    "SignatureDoesNotMatch",
+    "UnrecognizedClientException",
+    "AWS.SimpleQueueService.NonExistentQueue",
+];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum SqsErrorKind {
+    /// Network blips, throttling or server errors, worth retrying
+    Transient,
+    /// Invalid credentials or queue, the service can't recover by itself
+    Fatal,
+}
+
+pub fn classify(err: &Error) -> SqsErrorKind {
+    match err {
+        Error::QueueDoesNotExist(_)
+        | Error::InvalidAddress(_)
+        | Error::InvalidSecurity(_)
+        | Error::KmsAccessDenied(_)
+        | Error::KmsDisabled(_)
+        | Error::KmsInvalidKeyUsage(_)
+        | Error::KmsInvalidState(_)
+        | Error::KmsNotFound(_)
+        | Error::KmsOptInRequired(_)
+        | Error::UnsupportedOperation(_) => SqsErrorKind::Fatal,
+        Error::RequestThrottled(_) | Error::KmsThrottled(_) | Error::OverLimit(_) => {
+            SqsErrorKind::Transient
+        }
+        err if err.code().is_some_and(|code| FATAL_CODES.contains(&code)) => SqsErrorKind::Fatal,
+        err => match find_credentials_error(err) {
+            Some(
+                CredentialsError::CredentialsNotLoaded(_)
+                | CredentialsError::InvalidConfiguration(_),
+            ) => SqsErrorKind::Fatal,
+            _ => SqsErrorKind::Transient,
+        },
+    }
+}
+
+/// Credentials errors are buried in the dispatch failure, so walk through the source chain
+fn find_credentials_error<'a>(err: &'a (dyn StdError + 'static)) -> Option<&'a CredentialsError> {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<CredentialsError>() {
+            return Some(err);
+        }
+        source = err.source();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_sqs::{
+        error::{ConnectorError, SdkError},
+        operation::receive_message::ReceiveMessageError,
+        types::error::{QueueDoesNotExist, RequestThrottled},
+    };
+    use aws_smithy_types::error::ErrorMetadata;
+    use std::time::Duration;
+
+    fn dispatch_failure(source: impl StdError + Send + Sync + 'static) -> Error {
+        let err: SdkError<ReceiveMessageError, ()> =
+            SdkError::dispatch_failure(ConnectorError::other(Box::new(source), None));
+        Error::from(err)
+    }
+
+    #[test]
+    fn test_classify_service_error() {
+        let err = Error::QueueDoesNotExist(QueueDoesNotExist::builder().build());
+        assert_eq!(classify(&err), SqsErrorKind::Fatal);
+
+        let err = Error::RequestThrottled(RequestThrottled::builder().build());
+        assert_eq!(classify(&err), SqsErrorKind::Transient);
+
+        let err = Error::from(ReceiveMessageError::generic(
+            ErrorMetadata::builder()
+                .code("InvalidClientTokenId")
+                .build(),
+        ));
+        assert_eq!(classify(&err), SqsErrorKind::Fatal);
+
+        let err = Error::from(ReceiveMessageError::generic(
+            ErrorMetadata::builder().code("InternalError").build(),
+        ));
+        assert_eq!(classify(&err), SqsErrorKind::Transient);
+    }
+
+    #[test]
+    fn test_classify_credentials_error() {
+        let err = dispatch_failure(CredentialsError::not_loaded_no_source());
+        assert_eq!(classify(&err), SqsErrorKind::Fatal);
+
+        let err = dispatch_failure(CredentialsError::provider_timed_out(Duration::from_secs(5)));
+        assert_eq!(classify(&err), SqsErrorKind::Transient);
+    }
+
+    #[test]
+    fn test_classify_network_error() {
+        let err = dispatch_failure(std::io::Error::from(std::io::ErrorKind::ConnectionReset));
+        assert_eq!(classify(&err), SqsErrorKind::Transient);
+    }
+}
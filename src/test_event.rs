@@ -1,4 +1,5 @@
 use serde_derive::Deserialize;
+use std::str::FromStr;
 
 /// Test event when config S3 notifaction
 #[derive(Debug, Deserialize)]
@@ -9,11 +10,15 @@ pub struct TestEvent {
     bucket: Option<String>,
 }
 
-impl TestEvent {
-    pub fn from_str(input: &str) -> Result<Self, serde_json::Error> {
+impl FromStr for TestEvent {
+    type Err = serde_json::Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
         serde_json::from_str::<Self>(input)
     }
+}
 
+impl TestEvent {
     pub fn is_storipress_bucket(&self) -> bool {
         self.service == "Amazon S3"
             && self.event == "s3:TestEvent"
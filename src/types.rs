@@ -1,12 +1,37 @@
+use crate::{
+    environment::{Environment, EnvironmentRegistry},
+    extract::is_relative_inside,
+    pages::DeploymentResult,
+};
 use serde_derive::Deserialize;
+use std::{
+    path::{Component, Path},
+    str::FromStr,
+};
 use strum::AsRefStr;
-use tracing::warn;
 
-#[derive(Debug, Copy, Clone)]
+/// Latest version of the `sp-deploy` meta schema, meta without `version` is treated as version 1
+pub const META_VERSION: u32 = 1;
+
+#[derive(Debug, Clone)]
 pub struct FileSummary {
     pub ignored: i32,
     pub removed_fail: i32,
     pub removed_success: i32,
+    /// Result of the upload to the deploy target, empty for static deploys to Cloudflare
+    pub upload: UploadSummary,
+    /// Deployment created by the target, only Cloudflare Pages creates one
+    pub deployment: Option<DeploymentResult>,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct UploadSummary {
+    pub uploaded: i32,
+    /// Unchanged files which are already in R2
+    pub skipped: i32,
+    pub failed: i32,
+    /// The assets of the release are recorded in a manifest, stale assets can be collected
+    pub manifest_written: bool,
 }
 
 impl FileSummary {
@@ -15,49 +40,51 @@ impl FileSummary {
     }
 }
 
-#[derive(Deserialize, Debug, AsRefStr, Default, PartialEq, Eq, Copy, Clone)]
-#[serde(rename_all = "snake_case", from = "String")]
+#[derive(Debug, AsRefStr, Default, PartialEq, Eq, Copy, Clone)]
 pub enum DeployType {
     #[default]
     Static,
     CloudflareFunction,
 }
 
-impl From<String> for DeployType {
-    fn from(value: String) -> Self {
-        match value.as_str() {
-            "static" => DeployType::Static,
-            "cloudflare_function" => DeployType::CloudflareFunction,
-            _ => {
-                warn!("unknown deploy type {value}, default to static");
-                DeployType::Static
-            }
+impl FromStr for DeployType {
+    type Err = MetaError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "static" => Ok(DeployType::Static),
+            "cloudflare_function" => Ok(DeployType::CloudflareFunction),
+            _ => Err(MetaError::UnknownDeployType(value.to_owned())),
         }
     }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-pub enum ClientType {
-    Production,
-    Staging,
-    Development,
-    Unknown,
+/// Where the site is published, see [`crate::deploy_target`]
+#[derive(Debug, AsRefStr, Default, PartialEq, Eq, Copy, Clone)]
+pub enum TargetKind {
+    /// Cloudflare Pages, with the `_nuxt` assets in R2
+    #[default]
+    Cloudflare,
+    /// S3 compatible bucket served as a static website
+    S3Static,
+    /// Directory on the local file system, for testing
+    LocalDir,
 }
 
-impl ClientType {
-    #[inline]
-    pub fn api_base(&self) -> Option<&'static str> {
-        let base = match self {
-            ClientType::Production => "https://api.stori.press",
-            ClientType::Staging => "https://api.storipress.pro",
-            ClientType::Development => "https://api.storipress.dev",
-            ClientType::Unknown => return None,
-        };
-        Some(base)
+impl FromStr for TargetKind {
+    type Err = MetaError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "cloudflare" => Ok(TargetKind::Cloudflare),
+            "s3_static" => Ok(TargetKind::S3Static),
+            "local_dir" => Ok(TargetKind::LocalDir),
+            _ => Err(MetaError::UnknownTarget(value.to_owned())),
+        }
     }
 }
 
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Default)]
 pub struct DeployMeta {
     pub page_id: String,
     pub client_id: String,
@@ -66,15 +93,163 @@ pub struct DeployMeta {
     pub source: Option<String>,
     pub output_path: Option<String>,
     pub token: Option<String>,
-    #[serde(default)]
     pub deploy_type: DeployType,
+    pub target: TargetKind,
+
+    /// Size of the archive in bytes, verified before deploy when given
+    pub size: Option<u64>,
+    /// Hex encoded SHA-256 of the archive, verified before deploy when given
+    pub sha256: Option<String>,
+
+    /// Resolved from `client_id` when parsed, `None` when no environment matches
+    pub environment: Option<Environment>,
 
     #[cfg(feature = "intended_fail")]
-    #[serde(default)]
     pub __storipress_deployer_force_error: bool,
 }
 
+/// `sp-deploy` meta as sent by the generator, checked by [`DeployMeta::parse`]
+#[derive(Debug, Deserialize)]
+struct DeployMetaSchema {
+    version: Option<u32>,
+    #[serde(default)]
+    page_id: String,
+    #[serde(default)]
+    client_id: String,
+    #[serde(default)]
+    release_id: String,
+
+    source: Option<String>,
+    output_path: Option<String>,
+    token: Option<String>,
+    deploy_type: Option<String>,
+    target: Option<String>,
+
+    size: Option<u64>,
+    sha256: Option<String>,
+
+    #[cfg(feature = "intended_fail")]
+    #[serde(default)]
+    __storipress_deployer_force_error: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MetaError {
+    #[error("malformed meta: {0}")]
+    Malformed(#[from] serde_json::Error),
+    #[error("unsupported version {0}")]
+    UnsupportedVersion(u32),
+    #[error("{0} is empty")]
+    Empty(&'static str),
+    #[error("{0} is required")]
+    Missing(&'static str),
+    #[error("unknown deploy_type {0}")]
+    UnknownDeployType(String),
+    #[error("unknown target {0}")]
+    UnknownTarget(String),
+    #[error("{0} {1} is not a plain identifier")]
+    UnsafeId(&'static str, String),
+    #[error("output_path {0} escapes the extraction dir")]
+    UnsafeOutputPath(String),
+    #[error("sha256 {0} is not a hex encoded SHA-256")]
+    InvalidSha256(String),
+}
+
+/// A single path component made of ASCII letters, digits, `-`, `_` and `.`
+fn is_safe_id(value: &str) -> bool {
+    value
+        .bytes()
+        .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.'))
+        && matches!(
+            Path::new(value).components().collect::<Vec<_>>().as_slice(),
+            [Component::Normal(_)]
+        )
+}
+
 impl DeployMeta {
+    /// Parse and validate the `sp-deploy` meta, returns every problem found instead of the first
+    pub fn parse(json: &str, environments: &EnvironmentRegistry) -> Result<Self, Vec<MetaError>> {
+        let schema =
+            serde_json::from_str::<DeployMetaSchema>(json).map_err(|err| vec![err.into()])?;
+        let mut errors = Vec::new();
+
+        let version = schema.version.unwrap_or(1);
+        if version != META_VERSION {
+            // fields may mean something else in other versions
+            return Err(vec![MetaError::UnsupportedVersion(version)]);
+        }
+
+        for (name, value) in [
+            ("page_id", &schema.page_id),
+            ("client_id", &schema.client_id),
+            ("release_id", &schema.release_id),
+        ] {
+            if value.trim().is_empty() {
+                errors.push(MetaError::Empty(name));
+            }
+        }
+        // both name directories and object keys of the site
+        for (name, value) in [
+            ("client_id", &schema.client_id),
+            ("release_id", &schema.release_id),
+        ] {
+            if !value.is_empty() && !is_safe_id(value) {
+                errors.push(MetaError::UnsafeId(name, value.clone()));
+            }
+        }
+
+        let deploy_type = match schema.deploy_type.as_deref().map(DeployType::from_str) {
+            None => DeployType::default(),
+            Some(Ok(deploy_type)) => deploy_type,
+            Some(Err(err)) => {
+                errors.push(err);
+                DeployType::default()
+            }
+        };
+
+        let target = match schema.target.as_deref().map(TargetKind::from_str) {
+            None => TargetKind::default(),
+            Some(Ok(target)) => target,
+            Some(Err(err)) => {
+                errors.push(err);
+                TargetKind::default()
+            }
+        };
+
+        if let Some(output_path) = &schema.output_path {
+            if !is_relative_inside(Path::new(output_path)) {
+                errors.push(MetaError::UnsafeOutputPath(output_path.clone()));
+            }
+        }
+
+        if let Some(sha256) = &schema.sha256 {
+            if sha256.len() != 64 || !sha256.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                errors.push(MetaError::InvalidSha256(sha256.clone()));
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let environment = environments.resolve(&schema.client_id).cloned();
+        Ok(Self {
+            page_id: schema.page_id,
+            client_id: schema.client_id,
+            release_id: schema.release_id,
+            source: schema.source,
+            output_path: schema.output_path,
+            token: schema.token,
+            deploy_type,
+            target,
+            size: schema.size,
+            sha256: schema.sha256,
+            environment,
+            #[cfg(feature = "intended_fail")]
+            __storipress_deployer_force_error: schema.__storipress_deployer_force_error,
+        })
+    }
+
     pub fn derive_deploy_type_from_source(&mut self) {
         if matches!(
             self.source.as_deref(),
@@ -84,16 +259,6 @@ impl DeployMeta {
         }
     }
 
-    #[inline]
-    pub fn client_type(&self) -> ClientType {
-        match self.client_id.chars().next() {
-            Some('P') => ClientType::Production,
-            Some('S') => ClientType::Staging,
-            Some('D') => ClientType::Development,
-            _ => ClientType::Unknown,
-        }
-    }
-
     pub fn token(&self) -> &str {
         self.token.as_deref().unwrap_or("")
     }
@@ -107,8 +272,9 @@ impl DeployMeta {
         let client_id = &self.client_id;
 
         let host = self
-            .client_type()
-            .api_base()
+            .environment
+            .as_ref()
+            .map(|environment| &environment.api_base)
             .ok_or_else(|| anyhow::anyhow!("Fail to create api host url"))?;
 
         Ok(format!("{host}/client/{client_id}/graphql"))
@@ -166,4 +332,66 @@ mod tests {
 
         assert!(meta.deploy_type == DeployType::CloudflareFunction);
     }
+
+    #[test]
+    fn test_parse_meta() {
+        let environments = EnvironmentRegistry::default();
+        let meta = DeployMeta::parse(
+            r#"{"page_id":"p","client_id":"P1","release_id":"1","deploy_type":"cloudflare_function","output_path":"dist/public"}"#,
+            &environments,
+        )
+        .unwrap();
+        assert_eq!(meta.deploy_type, DeployType::CloudflareFunction);
+        assert_eq!(meta.target, TargetKind::Cloudflare);
+        assert_eq!(meta.output_path.as_deref(), Some("dist/public"));
+        assert_eq!(
+            meta.api_host().unwrap(),
+            "https://api.stori.press/client/P1/graphql"
+        );
+
+        let errors = DeployMeta::parse(
+            r#"{"version":1,"page_id":"","client_id":"P1","deploy_type":"lambda","target":"ftp","output_path":"../etc"}"#,
+            &environments,
+        )
+        .unwrap_err();
+        let errors = errors.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            [
+                "page_id is empty",
+                "release_id is empty",
+                "unknown deploy_type lambda",
+                "unknown target ftp",
+                "output_path ../etc escapes the extraction dir",
+            ]
+        );
+
+        assert!(matches!(
+            DeployMeta::parse(
+                r#"{"version":2,"page_id":"p","client_id":"P1","release_id":"1"}"#,
+                &environments
+            )
+            .unwrap_err()
+            .as_slice(),
+            [MetaError::UnsupportedVersion(2)]
+        ));
+        for (client_id, release_id) in [("/", "1"), ("P1", "../../.."), ("P1", ".."), ("P 1", "1")]
+        {
+            let json = format!(
+                r#"{{"page_id":"p","client_id":"{client_id}","release_id":"{release_id}"}}"#
+            );
+            assert!(matches!(
+                DeployMeta::parse(&json, &environments)
+                    .unwrap_err()
+                    .as_slice(),
+                [MetaError::UnsafeId(..)]
+            ));
+        }
+        assert!(matches!(
+            DeployMeta::parse("{", &environments)
+                .unwrap_err()
+                .as_slice(),
+            [MetaError::Malformed(_)]
+        ));
+    }
 }
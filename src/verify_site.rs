@@ -22,7 +22,7 @@ pub async fn verify_site(client: Client) {
 pub async fn verify_site_immediate(client: &Client) -> anyhow::Result<()> {
     info!(?client.meta, "start verify site");
 
-    let res = get_site(&client).await;
+    let res = get_site(client).await;
     let site = match res {
         Ok(Some(site)) => site,
         Ok(None) => {
@@ -52,7 +52,7 @@ pub async fn verify_site_immediate(client: &Client) -> anyhow::Result<()> {
     let storipress_url = site.customer_site_storipress_url();
     let url = format!("https://{storipress_url}");
 
-    verify_site_scripts(&client, &url).await
+    verify_site_scripts(client, &url).await
 }
 
 #[instrument(err)]
@@ -126,10 +126,7 @@ fn extract_scripts(html: String) -> Vec<String> {
 
 #[cfg(test)]
 mod tests {
-    use tracing_subscriber::{prelude::*, EnvFilter};
-
     use super::*;
-    use crate::types::DeployMeta;
 
     #[test]
     fn test_selector_work() {
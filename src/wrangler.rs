@@ -1,7 +1,14 @@
-use crate::{errors::ProcessFileError, retry::retry, types::DeployMeta};
+use crate::{
+    config::Config,
+    errors::{AggregateError, ProcessFileError},
+    pages::{DeploymentResult, PagesDeployer},
+    retry::retry,
+    types::DeployMeta,
+};
 use bstr::ByteSlice;
 use once_cell::sync::Lazy;
 use path_macro::path;
+use serde_derive::Deserialize;
 use std::{
     ffi::OsStr,
     fs,
@@ -13,58 +20,93 @@ use std::{
 use tokio::{
     io::{AsyncBufReadExt, AsyncRead, BufReader},
     process::Command,
+    sync::RwLock,
     task::JoinHandle,
     time::timeout,
 };
 use tracing::{info, instrument, warn};
 
-pub static WRANGLER_ROOT: &str = "/tmp/wrangler_root";
-static WRANGLER_CACHE_DIR: &str = "/tmp/wrangler_root/node_modules";
 static CREATE_DIR_ONCE: Once = Once::new();
-const WRANGLER_TIMEOUT_SECS: u64 = 60 * 20; // 20 minutes
-const WRANGLER_STATIC_TIMEOUT_SECS: u64 = 60 * 60; // 60 minutes
+/// Held for read by every running wrangler, and for write while cleaning up its junk files
+static WRANGLER_RUNNING: RwLock<()> = RwLock::const_new(());
 
 static WRANGLER_PATH: Lazy<PathBuf> = Lazy::new(|| {
     let pwd = std::env::current_dir().expect("Can't find current directory");
     path!(pwd / "node_modules" / ".bin" / "wrangler")
 });
 
-pub fn init() {
+/// HACK: path to trick wrangler and make it place cache in a writable path
+fn cache_dir(config: &Config) -> PathBuf {
+    config.wrangler.root.join("node_modules")
+}
+
+pub fn init(config: &Config) {
     CREATE_DIR_ONCE.call_once(|| {
-        if let Err(err) = fs::create_dir_all(WRANGLER_CACHE_DIR) {
+        if let Err(err) = fs::create_dir_all(cache_dir(config)) {
             sentry::capture_error(&err);
         }
     });
 }
 
-#[instrument(err)]
-pub async fn spawn(
+/// `wrangler pages deploy` run by Node, needs `node_modules` in the working dir
+#[derive(Debug)]
+pub struct Wrangler<'a> {
+    config: &'a Config,
+}
+
+impl<'a> Wrangler<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        Self { config }
+    }
+}
+
+impl PagesDeployer for Wrangler<'_> {
+    async fn deploy(
+        &self,
+        meta: &DeployMeta,
+        site_root: &Path,
+        deploy_path: &Path,
+    ) -> Result<DeploymentResult, ProcessFileError> {
+        spawn(self.config, meta, site_root, deploy_path).await
+    }
+}
+
+#[instrument(err, skip(config))]
+async fn spawn(
+    config: &Config,
     meta: &DeployMeta,
     site_root: &Path,
     deploy_path: &Path,
-) -> Result<(), ProcessFileError> {
-    // HACK: path to trick wrangler and make it place cache in a writable path
-    fs::create_dir_all(WRANGLER_CACHE_DIR)?;
-    let res = retry(|| async {
-        match timeout(
-            Duration::from_secs(if meta.is_static() {
-                WRANGLER_STATIC_TIMEOUT_SECS
-            } else {
-                WRANGLER_TIMEOUT_SECS
-            }),
-            do_spawn(meta, deploy_path, site_root),
-        )
-        .await
-        {
-            Ok(Ok(())) => Ok(()),
-            Ok(Err(err)) => Err(err),
-            Err(elapsed) => Err(ProcessFileError::WranglerTimeout(elapsed)),
+) -> Result<DeploymentResult, ProcessFileError> {
+    fs::create_dir_all(cache_dir(config))?;
+    let running = WRANGLER_RUNNING.read().await;
+    let res = retry(&config.retry, || async {
+        let limit = Duration::from_secs(if meta.is_static() {
+            config.wrangler.static_timeout_secs
+        } else {
+            config.wrangler.timeout_secs
+        });
+        match timeout(limit, do_spawn(meta, deploy_path, site_root)).await {
+            Ok(res) => res,
+            Err(_) => Err(ProcessFileError::WranglerTimeout(limit)),
         }
     })
     .await
-    .map_err(ProcessFileError::from);
+    .map_err(|errors| {
+        // a failure which was not retried keeps its own variant in Sentry
+        let mut errors = errors.into_vec();
+        if errors.len() == 1 {
+            *errors.remove(0)
+        } else {
+            AggregateError::from(errors).into()
+        }
+    });
 
-    cleanup_wrangler().await;
+    drop(running);
+    // other deployments may still be using the junk files
+    if let Ok(_cleaning) = WRANGLER_RUNNING.try_write() {
+        cleanup_wrangler().await;
+    }
 
     res
 }
@@ -73,9 +115,15 @@ async fn do_spawn(
     meta: &DeployMeta,
     deploy_path: &Path,
     site_root: &Path,
-) -> Result<(), ProcessFileError> {
+) -> Result<DeploymentResult, ProcessFileError> {
+    // outside of the site, or a retry would deploy it
+    let output_file = tempfile::Builder::new()
+        .prefix("wrangler-output-")
+        .suffix(".json")
+        .tempfile()?;
+
     let args = [
-        &*WRANGLER_PATH.as_os_str(),
+        WRANGLER_PATH.as_os_str(),
         "pages".as_ref(),
         "deploy".as_ref(),
         "--project-name".as_ref(),
@@ -87,11 +135,22 @@ async fn do_spawn(
 
     let wrangler_args = &args[1..];
     info!(args = ?wrangler_args, "run wrangler");
-    let mut child = Command::new("node")
+    let mut command = Command::new("node");
+    if let Some(account_id) = meta
+        .environment
+        .as_ref()
+        .and_then(|environment| environment.cloudflare_account_id.as_deref())
+    {
+        command.env("CLOUDFLARE_ACCOUNT_ID", account_id);
+    }
+    let mut child = command
+        .env("WRANGLER_OUTPUT_FILE_PATH", output_file.path())
         .args(args)
         .current_dir(site_root)
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
+        // stop deploying when the deployment is cancelled
+        .kill_on_drop(true)
         .spawn()?;
 
     let stdout_reader = spawn_reader("stdout", child.stdout.take(), |line| {
@@ -103,29 +162,42 @@ async fn do_spawn(
 
     let status = child.wait().await?;
     let success = status.success();
-    let _ = tokio::join!(stdout_reader, stderr_reader);
+    let (stdout, stderr) = tokio::join!(stdout_reader, stderr_reader);
+    let mut output = WranglerOutput::default();
+    for channel in [stdout, stderr].into_iter().flatten() {
+        output.merge(channel);
+    }
 
-    if success {
-        Ok(())
-    } else {
-        Err(ProcessFileError::DeployFail(status.code()))
+    if !success {
+        return Err(output
+            .failure
+            .unwrap_or(ProcessFileError::DeployFail(status.code())));
+    }
+
+    match tokio::fs::read_to_string(output_file.path()).await {
+        Ok(entries) => output.read_entries(&entries),
+        Err(err) => warn!(?err, "fail to read wrangler output file"),
     }
+    Ok(output.result)
 }
 
-async fn spawn_reader(
+/// Spawned before waiting for the child, or it would block on a full pipe
+fn spawn_reader(
     channel: &'static str,
     output: Option<impl AsyncRead + Send + Unpin + 'static>,
     log: fn(&str) -> (),
-) -> JoinHandle<()> {
+) -> JoinHandle<WranglerOutput> {
     tokio::spawn(async move {
         let reader = BufReader::new(output.unwrap_or_else(|| {
             panic!("Fail to get {}", channel);
         }));
         let mut lines = reader.lines();
+        let mut output = WranglerOutput::default();
         loop {
             match lines.next_line().await {
                 Ok(Some(line)) => {
                     log(&line);
+                    output.read_line(&line);
                 }
                 Ok(None) => break,
                 Err(err) => {
@@ -134,9 +206,122 @@ async fn spawn_reader(
                 }
             }
         }
+        output
     })
 }
 
+/// What `wrangler pages deploy` tells about the deployment, from its logs and its output file
+#[derive(Debug, Default)]
+struct WranglerOutput {
+    result: DeploymentResult,
+    /// First known error, the lines after it are often the same error in other words
+    failure: Option<ProcessFileError>,
+}
+
+/// Entry of the file at `WRANGLER_OUTPUT_FILE_PATH`, one JSON object per line
+#[derive(Debug, Deserialize)]
+struct OutputEntry {
+    #[serde(rename = "type")]
+    kind: String,
+    deployment_id: Option<String>,
+    url: Option<String>,
+}
+
+impl WranglerOutput {
+    fn read_line(&mut self, line: &str) {
+        let line = line.trim();
+
+        // `✨ Success! Uploaded 2 files (3 already uploaded) (0.45 sec)`
+        if let Some((_, rest)) = line.split_once("Uploaded ") {
+            self.result.uploaded = leading_number(rest).or(self.result.uploaded);
+            if let Some((counts, _)) = rest.split_once(" already uploaded") {
+                let skipped = counts
+                    .rsplit_once('(')
+                    .map_or(counts, |(_, skipped)| skipped);
+                self.result.skipped = leading_number(skipped);
+            }
+        }
+
+        // `✨ Deployment complete! Take a peek over at https://abc.project.pages.dev`
+        if let Some((_, url)) = line.split_once("Take a peek over at ") {
+            self.result.url = Some(url.trim().to_owned());
+        }
+
+        if let Some(failure) = failure(line) {
+            match (&self.failure, failure) {
+                // the file name comes on the line after the limit
+                (
+                    Some(ProcessFileError::PagesFileTooLarge(file)),
+                    ProcessFileError::PagesFileTooLarge(name),
+                ) if file.is_empty() => {
+                    self.failure = Some(ProcessFileError::PagesFileTooLarge(name))
+                }
+                (None, failure) => self.failure = Some(failure),
+                _ => {}
+            }
+        }
+    }
+
+    fn read_entries(&mut self, entries: &str) {
+        for entry in entries.lines().filter(|entry| !entry.trim().is_empty()) {
+            match serde_json::from_str::<OutputEntry>(entry) {
+                // `pages-deploy`, and `pages-deploy-detailed` in recent versions
+                Ok(entry) if entry.kind.starts_with("pages-deploy") => {
+                    self.result.deployment_id =
+                        entry.deployment_id.or(self.result.deployment_id.take());
+                    self.result.url = entry.url.or(self.result.url.take());
+                }
+                Ok(_) => {}
+                Err(err) => warn!(?err, entry, "unexpected wrangler output entry"),
+            }
+        }
+    }
+
+    fn merge(&mut self, other: WranglerOutput) {
+        let result = other.result;
+        self.result.deployment_id = self.result.deployment_id.take().or(result.deployment_id);
+        self.result.url = self.result.url.take().or(result.url);
+        self.result.uploaded = self.result.uploaded.or(result.uploaded);
+        self.result.skipped = self.result.skipped.or(result.skipped);
+        self.failure = self.failure.take().or(other.failure);
+    }
+}
+
+/// Known errors of wrangler and the Cloudflare API
+fn failure(line: &str) -> Option<ProcessFileError> {
+    let lower = line.to_ascii_lowercase();
+    if lower.contains("[code: 10000]")
+        || lower.contains("[code: 9109]")
+        || lower.contains("authentication error")
+        || lower.contains("not logged in")
+        || lower.contains("necessary to set a cloudflare_api_token")
+    {
+        Some(ProcessFileError::WranglerAuth)
+    } else if lower.contains("[code: 8000007]") || lower.contains("project not found") {
+        Some(ProcessFileError::PagesProjectNotFound)
+    } else if lower.contains("[code: 971]")
+        || lower.contains("rate limit")
+        || lower.contains("too many requests")
+    {
+        Some(ProcessFileError::PagesRateLimited)
+    } else if lower.contains("pages only supports files up to") {
+        Some(ProcessFileError::PagesFileTooLarge(String::new()))
+    } else if lower.ends_with(" in size") {
+        // `_nuxt/video.mp4 is 26.3 MB in size`
+        line.rsplit_once(" is ")
+            .map(|(name, _)| ProcessFileError::PagesFileTooLarge(name.to_owned()))
+    } else {
+        None
+    }
+}
+
+fn leading_number(text: &str) -> Option<u32> {
+    let end = text
+        .find(|char: char| !char.is_ascii_digit())
+        .unwrap_or(text.len());
+    text[..end].parse().ok()
+}
+
 /// Clean up the junk files leave by wrangler
 /// `/tmp/*.mjs`
 /// `/tmp/*.map`
@@ -189,3 +374,80 @@ fn to_bstr(s: &impl AsRef<OsStr>) -> &[u8] {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(lines: &str) -> WranglerOutput {
+        let mut output = WranglerOutput::default();
+        for line in lines.lines() {
+            output.read_line(line);
+        }
+        output
+    }
+
+    #[test]
+    fn test_read_output() {
+        let mut output = read(
+            "🌍  Uploading... (3/5)
+✨ Success! Uploaded 2 files (3 already uploaded) (0.45 sec)
+✨ Uploading _headers
+🌎 Deploying...
+✨ Deployment complete! Take a peek over at https://abc123.p1.pages.dev",
+        );
+        assert!(output.failure.is_none());
+        output.read_entries(
+            r#"{"version":1,"type":"wrangler-session","wrangler_version":"3.78.10"}
+{"version":1,"type":"pages-deploy-detailed","pages_project":"p1","deployment_id":"d1","url":"https://abc123.p1.pages.dev","alias":"https://p1-branch.p1.pages.dev"}
+"#,
+        );
+        assert_eq!(
+            output.result,
+            DeploymentResult {
+                deployment_id: Some("d1".to_owned()),
+                url: Some("https://abc123.p1.pages.dev".to_owned()),
+                uploaded: Some(2),
+                skipped: Some(3),
+            }
+        );
+    }
+
+    #[test]
+    fn test_read_failure() {
+        let output = read(
+            "✘ [ERROR] A request to the Cloudflare API (/accounts/a/pages/projects/p1) failed.
+  Authentication error [code: 10000]",
+        );
+        assert!(matches!(
+            output.failure,
+            Some(ProcessFileError::WranglerAuth)
+        ));
+
+        let output = read(
+            "✘ [ERROR] A request to the Cloudflare API (/accounts/a/pages/projects/p1) failed.
+  Project not found. The specified project name does not match any of your existing projects. [code: 8000007]",
+        );
+        assert!(matches!(
+            output.failure,
+            Some(ProcessFileError::PagesProjectNotFound)
+        ));
+
+        let output = read(
+            "✘ [ERROR] Error: Pages only supports files up to 26.2 MB in size
+  _nuxt/video.mp4 is 31.5 MB in size",
+        );
+        assert!(matches!(
+            output.failure,
+            Some(ProcessFileError::PagesFileTooLarge(name)) if name == "_nuxt/video.mp4"
+        ));
+
+        let output = read("  Too many requests. Please wait and consider throttling your request speed [code: 971]");
+        assert!(matches!(
+            output.failure,
+            Some(ProcessFileError::PagesRateLimited)
+        ));
+
+        assert!(read("✘ [ERROR] Something else").failure.is_none());
+    }
+}